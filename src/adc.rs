@@ -0,0 +1,356 @@
+//! Application Default Credentials, finds credentials the same way the
+//! official Google Cloud client libraries do:
+//!
+//! 1. the file pointed to by the `GOOGLE_APPLICATION_CREDENTIALS` environment variable
+//! 2. the well-known file written by `gcloud auth application-default login`
+//! 3. the metadata server, when running on Google Cloud
+//!
+//! The first source found is used. If a credentials file is found but cannot be
+//! loaded that is reported as an error rather than silently falling through to
+//! the next source.
+
+use crate::auth::Token;
+use crate::credentials::CredentialsFile;
+use crate::fetcher::TokenFetcher;
+use crate::metadata::MetadataServer;
+use crate::scopes::Scope;
+use crate::{GoErr, Result};
+
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use time::Duration;
+
+pub const CREDENTIALS_ENV_VAR: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+const CLOUDSDK_CONFIG_ENV_VAR: &str = "CLOUDSDK_CONFIG";
+const WELL_KNOWN_FILE: &str = "application_default_credentials.json";
+
+/// The place Application Default Credentials were found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdcSource {
+    /// The file pointed to by `GOOGLE_APPLICATION_CREDENTIALS`
+    EnvironmentVariable(PathBuf),
+    /// The gcloud well-known file, `~/.config/gcloud/application_default_credentials.json`
+    WellKnownFile(PathBuf),
+    /// The GCE metadata server
+    MetadataServer,
+}
+
+impl fmt::Display for AdcSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdcSource::EnvironmentVariable(path) => {
+                write!(f, "{} ({})", CREDENTIALS_ENV_VAR, path.display())
+            }
+            AdcSource::WellKnownFile(path) => {
+                write!(f, "gcloud well-known file ({})", path.display())
+            }
+            AdcSource::MetadataServer => write!(f, "metadata server"),
+        }
+    }
+}
+
+/// A source that was checked and passed over, along with the reason why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedSource {
+    pub source: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for SkippedSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.source, self.reason)
+    }
+}
+
+/// Credentials resolved by `find_default_credentials`, ready to fetch tokens.
+pub struct DefaultCredentials {
+    source: AdcSource,
+    skipped: Vec<SkippedSource>,
+    fetcher: TokenFetcher,
+}
+
+impl DefaultCredentials {
+    /// Where the credentials were found
+    pub fn source(&self) -> &AdcSource {
+        &self.source
+    }
+
+    /// Sources that were checked before `source` and why they were not used
+    pub fn skipped(&self) -> &[SkippedSource] {
+        &self.skipped
+    }
+
+    pub fn token_fetcher(&self) -> &TokenFetcher {
+        &self.fetcher
+    }
+
+    pub fn into_token_fetcher(self) -> TokenFetcher {
+        self.fetcher
+    }
+
+    /// Shorthand for `token_fetcher().fetch_token()`
+    pub fn fetch_token(&self) -> Result<Token> {
+        self.fetcher.fetch_token()
+    }
+}
+
+/// Finds Application Default Credentials and wraps them in a `TokenFetcher`.
+///
+/// `scopes` are used when the credentials are a service account key, tokens
+/// from the metadata server carry the scopes of the instance.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::adc::find_default_credentials;
+/// use goauth::scopes::Scope;
+/// use time::Duration;
+///
+/// let credentials = find_default_credentials(&[Scope::DevStorageReadWrite], Duration::new(60, 0)).unwrap();
+/// println!("using {}", credentials.source());
+/// let token = credentials.fetch_token().unwrap();
+/// ```
+pub fn find_default_credentials(
+    scopes: &[Scope],
+    refresh_buffer: Duration,
+) -> Result<DefaultCredentials> {
+    find_with(
+        env::var_os(CREDENTIALS_ENV_VAR).map(PathBuf::from),
+        well_known_file(),
+        MetadataServer::new(),
+        scopes,
+        refresh_buffer,
+    )
+}
+
+/// Location of the file written by `gcloud auth application-default login`
+pub fn well_known_file() -> Option<PathBuf> {
+    let config_dir = match env::var_os(CLOUDSDK_CONFIG_ENV_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?).join("gcloud"),
+        None => PathBuf::from(env::var_os("HOME")?)
+            .join(".config")
+            .join("gcloud"),
+    };
+    Some(config_dir.join(WELL_KNOWN_FILE))
+}
+
+fn find_with(
+    env_file: Option<PathBuf>,
+    well_known_file: Option<PathBuf>,
+    metadata: MetadataServer,
+    scopes: &[Scope],
+    refresh_buffer: Duration,
+) -> Result<DefaultCredentials> {
+    let mut skipped = Vec::new();
+
+    match env_file {
+        Some(path) => {
+            let fetcher = fetcher_from_file(&path, scopes, refresh_buffer)?;
+            return Ok(found(
+                AdcSource::EnvironmentVariable(path),
+                skipped,
+                fetcher,
+            ));
+        }
+        None => skipped.push(SkippedSource {
+            source: "environment variable",
+            reason: format!("{} is not set", CREDENTIALS_ENV_VAR),
+        }),
+    }
+
+    match well_known_file {
+        Some(path) if path.is_file() => {
+            let fetcher = fetcher_from_file(&path, scopes, refresh_buffer)?;
+            return Ok(found(AdcSource::WellKnownFile(path), skipped, fetcher));
+        }
+        Some(path) => skipped.push(SkippedSource {
+            source: "well-known file",
+            reason: format!("{} does not exist", path.display()),
+        }),
+        None => skipped.push(SkippedSource {
+            source: "well-known file",
+            reason: "could not determine the gcloud config directory".to_string(),
+        }),
+    }
+
+    if metadata.is_available() {
+        let fetcher = TokenFetcher::with_metadata_server(metadata, refresh_buffer);
+        return Ok(found(AdcSource::MetadataServer, skipped, fetcher));
+    }
+    skipped.push(SkippedSource {
+        source: "metadata server",
+        reason: format!("no metadata server responded at {}", metadata.host()),
+    });
+
+    let reasons = skipped
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>()
+        .join("; ");
+    Err(GoErr::from(
+        format!("could not find default credentials ({})", reasons).as_str(),
+    ))
+}
+
+fn found(
+    source: AdcSource,
+    skipped: Vec<SkippedSource>,
+    fetcher: TokenFetcher,
+) -> DefaultCredentials {
+    log::debug!("using application default credentials from {}", source);
+    for s in &skipped {
+        log::debug!("skipped {}", s);
+    }
+    DefaultCredentials {
+        source,
+        skipped,
+        fetcher,
+    }
+}
+
+fn fetcher_from_file(
+    path: &Path,
+    scopes: &[Scope],
+    refresh_buffer: Duration,
+) -> Result<TokenFetcher> {
+    path.to_str()
+        .ok_or_else(|| GoErr::from("credentials path is not valid UTF-8"))
+        .and_then(CredentialsFile::from_file)
+        .and_then(|credentials| credentials.into_token_fetcher(scopes, refresh_buffer))
+        .map_err(|e| {
+            GoErr::from(
+                format!("failed to load credentials from {}: {}", path.display(), e).as_str(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{self, mock};
+
+    fn metadata_mock() -> MetadataServer {
+        MetadataServer::with_host(&mockito::server_address().to_string())
+    }
+
+    fn unreachable_metadata() -> MetadataServer {
+        // Nothing listens on the discard port
+        MetadataServer::with_host("127.0.0.1:9")
+    }
+
+    #[test]
+    fn env_var_takes_precedence() {
+        let credentials = find_with(
+            Some(PathBuf::from("dummy_credentials_file_for_tests.json")),
+            Some(PathBuf::from("dummy_credentials_file_for_tests.json")),
+            unreachable_metadata(),
+            &[Scope::DevStorageReadWrite],
+            Duration::new(0, 0),
+        )
+        .unwrap();
+
+        assert_eq!(
+            credentials.source(),
+            &AdcSource::EnvironmentVariable(PathBuf::from("dummy_credentials_file_for_tests.json"))
+        );
+        assert!(credentials.skipped().is_empty());
+    }
+
+    #[test]
+    fn well_known_file_after_env_var() {
+        let credentials = find_with(
+            None,
+            Some(PathBuf::from("dummy_credentials_file_for_tests.json")),
+            unreachable_metadata(),
+            &[Scope::DevStorageReadWrite],
+            Duration::new(0, 0),
+        )
+        .unwrap();
+
+        assert_eq!(
+            credentials.source(),
+            &AdcSource::WellKnownFile(PathBuf::from("dummy_credentials_file_for_tests.json"))
+        );
+        assert_eq!(credentials.skipped().len(), 1);
+    }
+
+    #[test]
+    fn authorized_user_well_known_file() {
+        let path = env::temp_dir().join("goauth_adc_authorized_user.json");
+        std::fs::write(
+            &path,
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        )
+        .unwrap();
+
+        let credentials = find_with(
+            None,
+            Some(path.clone()),
+            unreachable_metadata(),
+            &[Scope::DevStorageReadWrite],
+            Duration::new(0, 0),
+        )
+        .unwrap();
+
+        assert_eq!(credentials.source(), &AdcSource::WellKnownFile(path));
+    }
+
+    #[test]
+    fn invalid_env_file_is_an_error() {
+        let result = find_with(
+            Some(PathBuf::from("does_not_exist.json")),
+            Some(PathBuf::from("dummy_credentials_file_for_tests.json")),
+            unreachable_metadata(),
+            &[Scope::DevStorageReadWrite],
+            Duration::new(0, 0),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn metadata_server_last() {
+        let _probe = mock("GET", "/")
+            .with_status(200)
+            .with_header("Metadata-Flavor", "Google")
+            .create();
+        let _token = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/token",
+        )
+        .match_header("Metadata-Flavor", "Google")
+        .with_status(200)
+        .with_body(r#"{"access_token":"token","token_type":"Bearer","expires_in":3599}"#)
+        .create();
+
+        let credentials = find_with(
+            None,
+            Some(PathBuf::from("does_not_exist.json")),
+            metadata_mock(),
+            &[Scope::DevStorageReadWrite],
+            Duration::new(0, 0),
+        )
+        .unwrap();
+
+        assert_eq!(credentials.source(), &AdcSource::MetadataServer);
+        assert_eq!(credentials.skipped().len(), 2);
+        assert_eq!(credentials.fetch_token().unwrap().access_token(), "token");
+    }
+
+    #[test]
+    fn nothing_found() {
+        let result = find_with(
+            None,
+            None,
+            unreachable_metadata(),
+            &[Scope::DevStorageReadWrite],
+            Duration::new(0, 0),
+        );
+
+        let err = result.err().unwrap().to_string();
+        assert!(err.contains(CREDENTIALS_ENV_VAR));
+        assert!(err.contains("metadata server"));
+    }
+}
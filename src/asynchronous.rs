@@ -0,0 +1,967 @@
+//! Async counterparts of `get_token` and `TokenFetcher`, enabled with the
+//! `async` feature. Requests are made with `reqwest`, so they need to run on a
+//! tokio runtime; other runtimes and HTTP clients aren't supported, run the
+//! blocking API on a thread pool there.
+//!
+//! `AsyncTokenFetcher` covers service account keys, the metadata server,
+//! authorized user, external account and impersonated credentials. Reading an
+//! external account's subject token, and fetching the base identity token of
+//! impersonated credentials, happen on tokio's blocking thread pool.
+//!
+//! The blocking API is unaffected, both can be used side by side.
+
+use crate::auth::{JwtClaims, Token, TokenErr};
+use crate::credentials::{
+    AuthorizedUserCredentials, Credentials, ExternalAccountAuthorizedUserCredentials,
+};
+use crate::external_account::ExternalAccountCredentials;
+use crate::fetcher::{refresh_after, refresher_backoff, refresher_delay};
+use crate::http::{HttpRequest, HttpResponse, Method};
+use crate::impersonate::{generate_access_token_response, ImpersonatedCredentials};
+use crate::metadata::{MetadataServer, METADATA_FLAVOR, METADATA_FLAVOR_VALUE};
+use crate::signer;
+use crate::{form_body, token_response, GoErr, Result};
+
+use arc_swap::ArcSwapOption;
+#[cfg(feature = "openssl")]
+use smpl_jwt::Jwt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration as StdDuration;
+use time::{Duration, OffsetDateTime};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Async get Token which can be used to authenticate further request
+///
+/// Deprecated: the key and header of `jwt` are ignored, only its claims are
+/// used. They are signed with the private key of `credentials`, with its
+/// `private_key_id` as the `kid` of the header. Use
+/// `AsyncTokenFetcher::with_service_account`.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::asynchronous::get_token;
+/// use goauth::auth::JwtClaims;
+/// use goauth::credentials::Credentials;
+/// use goauth::scopes::Scope;
+/// use smpl_jwt::Jwt;
+///
+/// # async fn run() {
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let claims = JwtClaims::new(credentials.iss(),
+///                             &[Scope::DevStorageReadWrite],
+///                             credentials.token_uri(),
+///                             None, None);
+/// let jwt = Jwt::new(claims, credentials.rsa_key().unwrap(), None);
+///
+/// let token = get_token(&jwt, &credentials).await.unwrap();
+/// # }
+/// ```
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `AsyncTokenFetcher::with_service_account`"
+)]
+#[allow(deprecated)]
+pub async fn get_token(jwt: &Jwt<JwtClaims>, credentials: &Credentials) -> Result<Token> {
+    get_token_with_client(jwt, credentials, &reqwest::Client::new()).await
+}
+
+/// Like `get_token`, with a `reqwest::Client` of your own so its connection
+/// pool, proxy and TLS configuration are reused. The key of `jwt` is ignored,
+/// as it is by `get_token`.
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `AsyncTokenFetcher::with_service_account`"
+)]
+pub async fn get_token_with_client(
+    jwt: &Jwt<JwtClaims>,
+    credentials: &Credentials,
+    client: &reqwest::Client,
+) -> Result<Token> {
+    let jwt_body = signer::encode(jwt.body(), credentials)?;
+
+    get_token_with_client_and_body(jwt_body, credentials, client).await
+}
+
+async fn get_token_with_client_and_body(
+    jwt_body: String,
+    credentials: &Credentials,
+    client: &reqwest::Client,
+) -> Result<Token> {
+    let response = client
+        .post(credentials.token_uri())
+        .form(&form_body(&jwt_body))
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        Ok(response.json::<Token>().await?)
+    } else {
+        let status = response.status().as_u16();
+        let token_err = TokenErr::from_response(status, &response.text().await?);
+        Err(GoErr::from(token_err))
+    }
+}
+
+async fn get_metadata_token(metadata: &MetadataServer, client: &reqwest::Client) -> Result<Token> {
+    let response = client
+        .get(metadata.token_url())
+        .header(METADATA_FLAVOR, METADATA_FLAVOR_VALUE)
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        Ok(response.json::<Token>().await?)
+    } else {
+        let status = response.status().as_u16();
+        let token_err = TokenErr::from_response(status, &response.text().await?);
+        Err(GoErr::endpoint("metadata server", token_err))
+    }
+}
+
+/// Sends `request` through `client`, the async counterpart of `HttpClient::send`
+async fn send(client: &reqwest::Client, request: HttpRequest) -> Result<HttpResponse> {
+    let mut builder = match request.method() {
+        Method::Get => client.get(request.url()),
+        Method::Post => client.post(request.url()),
+        Method::Put => client.put(request.url()),
+    };
+    for (name, value) in request.headers() {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    if let Some(timeout) = request.timeouts().total() {
+        builder = builder.timeout(timeout);
+    }
+
+    let response = builder.body(request.body().to_vec()).send().await?;
+    let status = response.status().as_u16();
+    Ok(HttpResponse::new(status, response.bytes().await?.to_vec()))
+}
+
+/// Runs `f` on tokio's blocking thread pool, for the parts of a token fetch
+/// that have no async counterpart
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| GoErr::from(format!("blocking token fetch failed: {}", e).as_str()))?
+}
+
+/// A future that can be held across tasks, as returned by `AsyncTokenSource`
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The async counterpart of `TokenSource`, implemented by the metadata server,
+/// authorized user, external account and impersonated credentials, and
+/// `AsyncTokenFetcher`. Accept an `Arc<dyn AsyncTokenSource>` to work with any
+/// of them, or with fakes in tests.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::asynchronous::{AsyncTokenFetcher, AsyncTokenSource};
+/// use goauth::metadata::MetadataServer;
+/// use std::sync::Arc;
+/// use time::Duration;
+///
+/// async fn list_buckets(auth: Arc<dyn AsyncTokenSource>) {
+///     let header = auth.authorization_header().await.unwrap();
+///     // ... send a request with `Authorization: {header}`
+/// }
+///
+/// # async fn run() {
+/// let fetcher = AsyncTokenFetcher::with_metadata_server(MetadataServer::new(), Duration::new(60, 0));
+/// list_buckets(Arc::new(fetcher)).await;
+/// # }
+/// ```
+pub trait AsyncTokenSource: Send + Sync {
+    /// Fetches a token, which for caching sources may be a stored one
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>>;
+
+    /// Like `get_token`, sending any requests through `client`. Sources that
+    /// make no requests, or bring their own client like `AsyncTokenFetcher`,
+    /// ignore it.
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        let _ = client;
+        self.get_token()
+    }
+
+    /// The value of an `Authorization` header carrying a token, e.g.
+    /// `Bearer ya29.abc`
+    fn authorization_header(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move {
+            let token = self.get_token().await?;
+            Ok(format!("{} {}", token.token_type(), token.access_token()))
+        })
+    }
+}
+
+impl<T: AsyncTokenSource + ?Sized> AsyncTokenSource for Arc<T> {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        (**self).get_token()
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        (**self).get_token_with_client(client)
+    }
+
+    fn authorization_header(&self) -> BoxFuture<'_, Result<String>> {
+        (**self).authorization_header()
+    }
+}
+
+impl AsyncTokenSource for MetadataServer {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(async move { get_metadata_token(self, &reqwest::Client::new()).await })
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        Box::pin(get_metadata_token(self, client))
+    }
+}
+
+impl AsyncTokenSource for AuthorizedUserCredentials {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(async move { self.get_token_with_client(&reqwest::Client::new()).await })
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        Box::pin(async move { token_response(send(client, self.token_request()).await?) })
+    }
+}
+
+impl AsyncTokenSource for ExternalAccountAuthorizedUserCredentials {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(async move { self.get_token_with_client(&reqwest::Client::new()).await })
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        Box::pin(async move { token_response(send(client, self.token_request()).await?) })
+    }
+}
+
+/// Reads the subject token on the blocking thread pool, as it may come from a
+/// file, an executable or a blocking request to its url or to AWS. The token
+/// exchange and impersonation are sent through the async client.
+impl AsyncTokenSource for ExternalAccountCredentials {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(async move { self.get_token_with_client(&reqwest::Client::new()).await })
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        Box::pin(async move {
+            let credentials = self.clone();
+            let subject_token = blocking(move || credentials.subject_token()).await?;
+            let token = token_response(send(client, self.exchange_request(&subject_token)).await?)?;
+
+            match self.impersonation_request(&token)? {
+                Some(request) => generate_access_token_response(send(client, request).await?),
+                None => Ok(token),
+            }
+        })
+    }
+}
+
+/// Fetches the base identity token from its blocking `TokenFetcher` on the
+/// blocking thread pool, a cached one is returned right away. The
+/// `generateAccessToken` request is sent through the async client.
+impl AsyncTokenSource for ImpersonatedCredentials {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(async move { self.get_token_with_client(&reqwest::Client::new()).await })
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        Box::pin(async move {
+            let source = self.shared_source();
+            let base_token = blocking(move || source.get_token()).await?;
+            let request = self.access_token_request(&base_token)?;
+            generate_access_token_response(send(client, request).await?)
+        })
+    }
+}
+
+/// JWTs for `claims`, signed with the private key of the service account and
+/// exchanged at its token endpoint
+struct ServiceAccountSource {
+    claims: Mutex<JwtClaims>,
+    credentials: Credentials,
+}
+
+impl AsyncTokenSource for ServiceAccountSource {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(async move { self.get_token_with_client(&reqwest::Client::new()).await })
+    }
+
+    fn get_token_with_client<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<Token>> {
+        Box::pin(async move {
+            let jwt_body = {
+                let mut claims = self.claims.lock().unwrap_or_else(PoisonError::into_inner);
+                let now = OffsetDateTime::now_utc();
+                claims.update(Some(now.unix_timestamp()), None);
+                signer::encode(&*claims, &self.credentials)?
+            };
+            get_token_with_client_and_body(jwt_body, &self.credentials, client).await
+        })
+    }
+}
+
+struct TokenState {
+    token: Token,
+    refresh_at: OffsetDateTime,
+}
+
+/// The async counterpart of `TokenFetcher`, it stores a `Token` on first fetch
+/// and keeps returning it until it is within `refresh_buffer` of expiring.
+pub struct AsyncTokenFetcher {
+    source: Arc<dyn AsyncTokenSource>,
+    client: reqwest::Client,
+    token_state: ArcSwapOption<TokenState>,
+    /// Held while refreshing, so concurrent tasks don't all hit the token endpoint
+    refresh_lock: tokio::sync::Mutex<()>,
+    refresh_buffer: Duration,
+    /// Set once a token that doesn't outlive `refresh_buffer` has been warned about
+    short_lived_warned: AtomicBool,
+    /// Stops the background refresher, if one was spawned, when the fetcher is dropped
+    refresher: Mutex<Option<Arc<StopSignal>>>,
+}
+
+impl AsyncTokenFetcher {
+    /// Deprecated like `with_client`, with the default `reqwest::Client`
+    #[cfg(feature = "openssl")]
+    #[deprecated(
+        since = "0.17.0",
+        note = "the key of the `Jwt` is ignored, use `AsyncTokenFetcher::with_service_account`"
+    )]
+    #[allow(deprecated)]
+    pub fn new(
+        jwt: Jwt<JwtClaims>,
+        credentials: Credentials,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_client(jwt, credentials, refresh_buffer, reqwest::Client::new())
+    }
+
+    /// Fetches tokens for the claims of `jwt`, sending requests through
+    /// `client`. Deprecated: the key and header of `jwt` are ignored, the
+    /// claims are signed with the private key of `credentials`.
+    #[cfg(feature = "openssl")]
+    #[deprecated(
+        since = "0.17.0",
+        note = "the key of the `Jwt` is ignored, use `AsyncTokenFetcher::with_service_account`"
+    )]
+    pub fn with_client(
+        jwt: Jwt<JwtClaims>,
+        credentials: Credentials,
+        refresh_buffer: Duration,
+        client: reqwest::Client,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_service_account(credentials, jwt.body().clone(), refresh_buffer)
+            .with_http_client(client)
+    }
+
+    /// Fetches tokens for `claims`, signed with the private key of
+    /// `credentials`, with its `private_key_id` as the `kid`. Available
+    /// without the `openssl` feature.
+    pub fn with_service_account(
+        credentials: Credentials,
+        claims: JwtClaims,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source(
+            Arc::new(ServiceAccountSource {
+                claims: Mutex::new(claims),
+                credentials,
+            }),
+            refresh_buffer,
+        )
+    }
+
+    /// Fetches tokens from the metadata server, for workloads on GCE, GKE
+    /// and Cloud Run that have no key file
+    pub fn with_metadata_server(
+        metadata: MetadataServer,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source(Arc::new(metadata), refresh_buffer)
+    }
+
+    /// Fetches tokens by exchanging the refresh token of an end user, for
+    /// running locally with `gcloud auth application-default login`
+    pub fn with_authorized_user(
+        credentials: AuthorizedUserCredentials,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Fetches tokens by exchanging the refresh token of a workforce identity
+    /// federation user at the Security Token Service
+    pub fn with_external_account_authorized_user(
+        credentials: ExternalAccountAuthorizedUserCredentials,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Fetches tokens through Workload Identity Federation, exchanging a
+    /// subject token from a third party identity provider
+    pub fn with_external_account(
+        credentials: ExternalAccountCredentials,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Fetches tokens of a service account impersonated by a base identity,
+    /// the base identity's tokens are cached by its own `TokenFetcher`
+    pub fn with_impersonation(
+        credentials: ImpersonatedCredentials,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Caches the tokens of any `AsyncTokenSource`, e.g. one of your own or a
+    /// fake in tests
+    pub fn with_source(
+        source: Arc<dyn AsyncTokenSource>,
+        refresh_buffer: Duration,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher::with_source_and_client(source, refresh_buffer, reqwest::Client::new())
+    }
+
+    /// Sends token requests through `client`, so its connection pool, proxy
+    /// and TLS configuration are reused
+    pub fn with_http_client(mut self, client: reqwest::Client) -> AsyncTokenFetcher {
+        self.client = client;
+        self
+    }
+
+    fn with_source_and_client(
+        source: Arc<dyn AsyncTokenSource>,
+        refresh_buffer: Duration,
+        client: reqwest::Client,
+    ) -> AsyncTokenFetcher {
+        AsyncTokenFetcher {
+            source,
+            client,
+            token_state: ArcSwapOption::from(None),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            refresh_buffer,
+            short_lived_warned: AtomicBool::new(false),
+            refresher: Mutex::new(None),
+        }
+    }
+
+    /// Returns the stored token while it is valid per its `expires_in` and the
+    /// configured `refresh_buffer`, otherwise fetches, stores and returns a
+    /// new one.
+    pub async fn fetch_token(&self) -> Result<Token> {
+        if let Some(token) = self.valid_token() {
+            return Ok(token);
+        }
+
+        // Refreshes are single-flight: one task fetches a new token while the
+        // others wait for it, and are then served the token it stored
+        let _refreshing = self.refresh_lock.lock().await;
+
+        match self.valid_token() {
+            Some(token) => Ok(token),
+            None => self.request_token().await,
+        }
+    }
+
+    /// The stored token, unless there is none yet or it is time to refresh it
+    fn valid_token(&self) -> Option<Token> {
+        let token_state = self.token_state.load();
+        let token_state = token_state.as_ref()?;
+
+        if OffsetDateTime::now_utc() >= token_state.refresh_at {
+            None
+        } else {
+            Some(token_state.token.clone())
+        }
+    }
+
+    /// The time at which the stored token needs to be refreshed, if there is one
+    fn refresh_at(&self) -> Option<OffsetDateTime> {
+        self.token_state
+            .load()
+            .as_ref()
+            .map(|token_state| token_state.refresh_at)
+    }
+
+    /// Refreshes the token even if the stored one is still valid, joining a
+    /// refresh already in flight
+    async fn refresh(&self) -> Result<Token> {
+        let _refreshing = self.refresh_lock.lock().await;
+
+        self.request_token().await
+    }
+
+    /// Spawns a task on the current tokio runtime that refreshes the token
+    /// shortly before `refresh_at`, like `TokenFetcher::spawn_background_refresh`.
+    /// The task stops when the fetcher is dropped or the handle is shut down.
+    pub fn spawn_background_refresh(self: &Arc<Self>) -> AsyncRefreshHandle {
+        let signal = Arc::new(StopSignal::default());
+        let previous = self
+            .refresher
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(signal.clone());
+        if let Some(previous) = previous {
+            previous.stop();
+        }
+
+        let task = tokio::spawn(run_refresher(Arc::downgrade(self), signal.clone()));
+
+        AsyncRefreshHandle { signal, task }
+    }
+
+    /// Refresh the token
+    async fn request_token(&self) -> Result<Token> {
+        let now = OffsetDateTime::now_utc();
+        let token = self.source.get_token_with_client(&self.client).await?;
+
+        let expires_in = Duration::new(token.expires_in().into(), 0);
+        let refresh_at =
+            now + refresh_after(expires_in, self.refresh_buffer, &self.short_lived_warned);
+        self.token_state.store(Some(Arc::new(TokenState {
+            token: token.clone(),
+            refresh_at,
+        })));
+        Ok(token)
+    }
+}
+
+impl Drop for AsyncTokenFetcher {
+    fn drop(&mut self) {
+        let refresher = self
+            .refresher
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(signal) = refresher.take() {
+            signal.stop();
+        }
+    }
+}
+
+/// Serves the cached token, the fetcher's own client is used to refresh it
+impl AsyncTokenSource for AsyncTokenFetcher {
+    fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+        Box::pin(self.fetch_token())
+    }
+}
+
+/// Tells a background refresh task to stop, waking it up if it is waiting
+#[derive(Default)]
+struct StopSignal {
+    stopped: AtomicBool,
+    notify: Notify,
+}
+
+impl StopSignal {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Stores a permit if the task isn't waiting right now
+        self.notify.notify_one();
+    }
+
+    /// Waits for `timeout` or until stopped, returns whether we were stopped
+    async fn wait(&self, timeout: StdDuration) -> bool {
+        if !self.stopped.load(Ordering::SeqCst) {
+            let _ = tokio::time::timeout(timeout, self.notify.notified()).await;
+        }
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Controls the task spawned by `AsyncTokenFetcher::spawn_background_refresh`.
+/// Dropping the handle leaves the task running until the fetcher is dropped.
+pub struct AsyncRefreshHandle {
+    signal: Arc<StopSignal>,
+    task: JoinHandle<()>,
+}
+
+impl AsyncRefreshHandle {
+    /// Whether the refresh task is still running
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Stops the refresh task and waits for it to finish. A refresh in flight
+    /// is completed first.
+    pub async fn shutdown(self) {
+        self.signal.stop();
+        let _ = self.task.await;
+    }
+}
+
+async fn run_refresher(fetcher: Weak<AsyncTokenFetcher>, signal: Arc<StopSignal>) {
+    let mut backoff = None;
+
+    loop {
+        let refresh_at = match fetcher.upgrade() {
+            Some(fetcher) => fetcher.refresh_at(),
+            None => return,
+        };
+        if signal.wait(refresher_delay(backoff, refresh_at)).await {
+            return;
+        }
+
+        let (result, refresh_at) = match fetcher.upgrade() {
+            Some(fetcher) => (fetcher.refresh().await, fetcher.refresh_at()),
+            None => return,
+        };
+        backoff = refresher_backoff(backoff, &result, refresh_at);
+    }
+}
+
+#[cfg(test)]
+// The deprecated `Jwt` constructors are still covered here
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use crate::scopes::Scope;
+    use crate::source::tests::FakeSource;
+    use mockito::{self, mock, Matcher};
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+
+    #[cfg(feature = "openssl")]
+    fn get_mocks() -> (Jwt<JwtClaims>, Credentials) {
+        let mut credentials =
+            Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        credentials.token_uri = format!("{}/async", mockito::server_url());
+
+        let claims = JwtClaims::new(
+            credentials.iss(),
+            &[Scope::DevStorageReadWrite],
+            credentials.token_uri(),
+            None,
+            None,
+        );
+        let jwt = Jwt::new(claims, credentials.rsa_key().unwrap(), None);
+
+        (jwt, credentials)
+    }
+
+    fn token_json(access_token: &str) -> String {
+        serde_json::json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "expires_in": 3600
+        })
+        .to_string()
+    }
+
+    #[cfg(feature = "openssl")]
+    #[tokio::test]
+    async fn async_get_token() {
+        let (jwt, credentials) = get_mocks();
+        let _mock = mock("POST", "/async")
+            .with_status(200)
+            .with_body(token_json("async_token"))
+            .create();
+
+        let token = get_token(&jwt, &credentials).await.unwrap();
+        assert_eq!(token.access_token(), "async_token");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[tokio::test]
+    async fn async_token_error() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/async_error", mockito::server_url());
+        let _mock = mock("POST", "/async_error")
+            .with_status(400)
+            .with_body(r#"{"error":"invalid_grant","error_description":"Invalid JWT"}"#)
+            .create();
+
+        let err = get_token(&jwt, &credentials).await.unwrap_err();
+        assert!(err.to_string().contains("Invalid JWT"));
+    }
+
+    #[cfg(feature = "openssl")]
+    #[tokio::test]
+    async fn async_fetcher_caches() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/async_cached", mockito::server_url());
+        let mock = mock("POST", "/async_cached")
+            .with_status(200)
+            .with_body(token_json("cached_token"))
+            .expect(1)
+            .create();
+
+        let fetcher = AsyncTokenFetcher::new(jwt, credentials, Duration::new(60, 0));
+        let first = fetcher.fetch_token().await.unwrap();
+        let second = fetcher.fetch_token().await.unwrap();
+
+        assert_eq!(first, second);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn async_service_account_token() {
+        let mut credentials =
+            Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        credentials.token_uri = format!("{}/async_service_account", mockito::server_url());
+        let claims = JwtClaims::new(
+            credentials.iss(),
+            &[Scope::DevStorageReadWrite],
+            credentials.token_uri(),
+            None,
+            None,
+        );
+        // The encoded `{"alg":"RS256","typ":"JWT","kid":"dummy"}`
+        let _mock = mock("POST", "/async_service_account")
+            .match_body(Matcher::Regex(
+                "assertion=eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6ImR1bW15In0".to_string(),
+            ))
+            .with_status(200)
+            .with_body(token_json("service_account_token"))
+            .create();
+
+        let fetcher =
+            AsyncTokenFetcher::with_service_account(credentials, claims, Duration::new(60, 0));
+        let token = fetcher.fetch_token().await.unwrap();
+
+        assert_eq!(token.access_token(), "service_account_token");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn async_refresh_is_single_flight() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/async_single_flight", mockito::server_url());
+        let mock = mock("POST", "/async_single_flight")
+            .with_status(200)
+            .with_body(token_json("single_flight"))
+            .expect(1)
+            .create();
+
+        let fetcher = Arc::new(AsyncTokenFetcher::new(
+            jwt,
+            credentials,
+            Duration::new(60, 0),
+        ));
+        let tasks = (0..16)
+            .map(|_| {
+                let fetcher = fetcher.clone();
+                tokio::spawn(async move { fetcher.fetch_token().await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().access_token(), "single_flight");
+        }
+        mock.assert();
+    }
+
+    /// Hands out tokens `short_{n}` that are due for a refresh a second
+    /// after they are fetched, waking up the test on each
+    struct ShortLivedSource {
+        calls: AtomicUsize,
+        fetched: tokio::sync::Notify,
+    }
+
+    impl AsyncTokenSource for ShortLivedSource {
+        fn get_token(&self) -> BoxFuture<'_, Result<Token>> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            self.fetched.notify_one();
+            let token = Token::new(format!("short_{}", call), "Bearer".to_string(), 2);
+            Box::pin(async move { Ok(token) })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn async_background_refresh() {
+        let source = Arc::new(ShortLivedSource {
+            calls: AtomicUsize::new(0),
+            fetched: tokio::sync::Notify::new(),
+        });
+        let fetcher = Arc::new(AsyncTokenFetcher::with_source(
+            source.clone(),
+            Duration::new(1, 0),
+        ));
+
+        // With the clock paused, the refresher's waits end as soon as every
+        // task is idle, so the refresh after the first fetch comes right away
+        let refresher = fetcher.spawn_background_refresh();
+        while source.calls.load(Ordering::SeqCst) < 2 {
+            source.fetched.notified().await;
+        }
+        assert!(refresher.is_running());
+        assert_ne!(
+            fetcher.fetch_token().await.unwrap().access_token(),
+            "short_0"
+        );
+
+        drop(fetcher);
+        tokio::time::timeout(StdDuration::from_secs(10), async {
+            while refresher.is_running() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_metadata_token() {
+        let metadata = MetadataServer::with_host(&mockito::server_address().to_string())
+            .with_account("async@project.iam.gserviceaccount.com");
+        let _mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/async@project.iam.gserviceaccount.com/token",
+        )
+        .match_header("Metadata-Flavor", "Google")
+        .with_status(200)
+        .with_body(token_json("metadata_token"))
+        .create();
+
+        let fetcher = AsyncTokenFetcher::with_metadata_server(metadata, Duration::new(60, 0));
+        assert_eq!(
+            fetcher.fetch_token().await.unwrap().access_token(),
+            "metadata_token"
+        );
+    }
+
+    fn authorized_user(token_path: &str) -> AuthorizedUserCredentials {
+        let mut credentials = AuthorizedUserCredentials::from_str(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        )
+        .unwrap();
+        credentials.token_uri = format!("{}{}", mockito::server_url(), token_path);
+        credentials
+    }
+
+    #[tokio::test]
+    async fn async_authorized_user_token() {
+        let mock = mock("POST", "/async_user")
+            .match_body(Matcher::UrlEncoded(
+                "refresh_token".to_string(),
+                "refresh".to_string(),
+            ))
+            .with_status(200)
+            .with_body(token_json("user_token"))
+            .create();
+
+        let fetcher = AsyncTokenFetcher::with_authorized_user(
+            authorized_user("/async_user"),
+            Duration::new(60, 0),
+        )
+        .with_http_client(reqwest::Client::new());
+        assert_eq!(
+            fetcher.fetch_token().await.unwrap().access_token(),
+            "user_token"
+        );
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn async_external_account_token() {
+        let path = std::env::temp_dir().join("goauth_async_external_account");
+        std::fs::write(&path, "async_subject_token").unwrap();
+        let credentials = ExternalAccountCredentials::from_str(
+            &serde_json::json!({
+                "type": "external_account",
+                "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider",
+                "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+                "token_url": format!("{}/async_sts", mockito::server_url()),
+                "credential_source": { "file": path },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let mock = mock("POST", "/async_sts")
+            .match_body(Matcher::UrlEncoded(
+                "subject_token".to_string(),
+                "async_subject_token".to_string(),
+            ))
+            .with_status(200)
+            .with_body(token_json("sts_token"))
+            .create();
+
+        let token = AsyncTokenSource::get_token(&credentials).await.unwrap();
+        assert_eq!(token.access_token(), "sts_token");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn async_impersonated_token() {
+        let _base = mock("POST", "/async_base")
+            .with_status(200)
+            .with_body(token_json("base_token"))
+            .create();
+        let impersonation = mock("POST", "/async_impersonate")
+            .match_header("Authorization", "Bearer base_token")
+            .with_status(200)
+            .with_body(r#"{"accessToken":"impersonated","expireTime":"2099-01-01T00:00:00Z"}"#)
+            .create();
+
+        let source = crate::fetcher::TokenFetcher::with_authorized_user(
+            authorized_user("/async_base"),
+            Duration::new(60, 0),
+        );
+        let mut credentials = ImpersonatedCredentials::new(
+            Arc::new(source),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::DevStorageReadWrite],
+        );
+        credentials.url = format!("{}/async_impersonate", mockito::server_url());
+
+        let token = AsyncTokenSource::get_token(&credentials).await.unwrap();
+        assert_eq!(token.access_token(), "impersonated");
+        impersonation.assert();
+    }
+
+    #[tokio::test]
+    async fn async_fetcher_caches_any_source() {
+        let source = Arc::new(FakeSource::new());
+        let fetcher = Arc::new(AsyncTokenFetcher::with_source(
+            source.clone(),
+            Duration::new(60, 0),
+        ));
+        let fetcher: Arc<dyn AsyncTokenSource> = fetcher;
+
+        assert_eq!(
+            fetcher.authorization_header().await.unwrap(),
+            "Bearer fake_0"
+        );
+        assert_eq!(fetcher.get_token().await.unwrap().access_token(), "fake_0");
+        assert_eq!(source.calls(), 1);
+    }
+}
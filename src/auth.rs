@@ -7,7 +7,11 @@ use crate::GoErr;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JwtClaims {
     iss: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     scope: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     aud: String,
     exp: i64,
     iat: i64,
@@ -59,6 +63,7 @@ impl JwtClaims {
         };
         JwtClaims {
             iss: service_acc_id,
+            sub: None,
             scope: scopes
                 .iter()
                 .map(|scope| scope.url())
@@ -69,6 +74,62 @@ impl JwtClaims {
             iat,
         }
     }
+
+    /// Sets the `sub` claim to the email of a Workspace user, for service accounts
+    /// with domain-wide delegation to act on the user's behalf
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use goauth::auth::JwtClaims;
+    /// use goauth::scopes::Scope;
+    ///
+    /// let claims = JwtClaims::new(String::from("sa@project.iam.gserviceaccount.com"),
+    ///                             &[Scope::GmailReadOnly],
+    ///                             String::from("https://oauth2.googleapis.com/token"),
+    ///                             None, None)
+    ///     .with_subject("user@example.com");
+    /// assert_eq!(claims.subject(), Some("user@example.com"));
+    /// ```
+    pub fn with_subject(mut self, subject: &str) -> Self {
+        self.sub = Some(subject.to_string());
+        self
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.sub.as_deref()
+    }
+
+    /// The `aud` claim, the token endpoint the JWT is exchanged at, or the API
+    /// a self-signed JWT is sent to
+    pub fn audience(&self) -> &str {
+        &self.aud
+    }
+
+    /// Claims of a JWT that is sent to a Google API as the bearer token itself,
+    /// valid for either an API `audience` such as `https://pubsub.googleapis.com/`
+    /// or a set of scopes. The subject is the service account itself.
+    pub(crate) fn self_signed(
+        service_acc_id: String,
+        scopes: &[Scope],
+        audience: Option<String>,
+        expires_after: i64,
+    ) -> Self {
+        let mut claims = JwtClaims::new(
+            service_acc_id.clone(),
+            scopes,
+            audience.unwrap_or_default(),
+            None,
+            Some(expires_after),
+        );
+        claims.sub = Some(service_acc_id);
+        claims
+    }
+
+    /// Seconds between `iat` and `exp`
+    pub(crate) fn lifetime(&self) -> i64 {
+        self.exp - self.iat
+    }
 }
 
 impl std::fmt::Display for JwtClaims {
@@ -77,15 +138,116 @@ impl std::fmt::Display for JwtClaims {
     }
 }
 
+/// An error response of a token or credentials endpoint
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TokenErr {
     error: String,
+    #[serde(default)]
     error_description: String,
+    /// The HTTP status, when the error was built from a response
+    #[serde(skip)]
+    status: Option<u16>,
+    /// The raw response body
+    #[serde(skip)]
+    body: String,
+}
+
+/// OAuth error codes, and Google API statuses, that mean the credentials or
+/// their permissions need fixing rather than the request retrying
+const AUTH_MISCONFIGURATION_ERRORS: &[&str] = &[
+    "invalid_grant",
+    "invalid_client",
+    "invalid_scope",
+    "unauthorized_client",
+    "access_denied",
+    "PERMISSION_DENIED",
+    "UNAUTHENTICATED",
+];
+
+impl TokenErr {
+    /// Builds the error out of a non-success response. Understands OAuth
+    /// errors (`{"error": "invalid_grant", "error_description": ".."}`) and
+    /// Google API errors (`{"error": {"status": "PERMISSION_DENIED", "message": ".."}}`),
+    /// any other body is only kept as is.
+    pub fn from_response(status: u16, body: &str) -> TokenErr {
+        let json = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+        let (error, error_description) = match &json["error"] {
+            serde_json::Value::String(error) => (
+                error.clone(),
+                json["error_description"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            serde_json::Value::Object(error) => (
+                error
+                    .get("status")
+                    .and_then(|status| status.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                error
+                    .get("message")
+                    .and_then(|message| message.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            _ => (String::new(), String::new()),
+        };
+
+        TokenErr {
+            error,
+            error_description,
+            status: Some(status),
+            body: body.to_string(),
+        }
+    }
+
+    /// Google's error code, e.g. `invalid_grant`, `invalid_scope` or
+    /// `PERMISSION_DENIED`. Empty if the response didn't carry one.
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+
+    pub fn error_description(&self) -> &str {
+        &self.error_description
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// Server errors and rate limiting, which may go away on their own
+    pub fn is_retryable(&self) -> bool {
+        match self.status {
+            Some(status) => status >= 500 || status == 429,
+            None => false,
+        }
+    }
+
+    /// Errors that won't go away until the credentials, their scopes or their
+    /// permissions are fixed
+    pub fn is_auth_misconfiguration(&self) -> bool {
+        AUTH_MISCONFIGURATION_ERRORS.contains(&self.error.as_str())
+            || matches!(self.status, Some(401) | Some(403))
+    }
 }
 
 impl std::fmt::Display for TokenErr {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "TokenErr: {}", self.error_description)
+        if !self.error_description.is_empty() {
+            write!(f, "TokenErr: {}", self.error_description)
+        } else if !self.error.is_empty() {
+            write!(f, "TokenErr: {}", self.error)
+        } else {
+            match self.status {
+                Some(status) => write!(f, "TokenErr: {} {}", status, self.body),
+                None => write!(f, "TokenErr: {}", self.body),
+            }
+        }
     }
 }
 
@@ -117,6 +279,14 @@ impl std::fmt::Display for Token {
 }
 
 impl Token {
+    pub(crate) fn new(access_token: String, token_type: String, expires_in: u32) -> Token {
+        Token {
+            access_token,
+            token_type,
+            expires_in,
+        }
+    }
+
     pub fn access_token(&self) -> &str {
         &self.access_token
     }
@@ -139,3 +309,43 @@ impl FromStr for Token {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oauth_error() {
+        let body = r#"{"error":"invalid_grant","error_description":"Invalid JWT Signature."}"#;
+        let err = TokenErr::from_response(400, body);
+
+        assert_eq!(err.error(), "invalid_grant");
+        assert_eq!(err.error_description(), "Invalid JWT Signature.");
+        assert_eq!(err.status(), Some(400));
+        assert_eq!(err.body(), body);
+        assert!(err.is_auth_misconfiguration());
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn google_api_error() {
+        let body =
+            r#"{"error":{"code":403,"message":"Permission denied","status":"PERMISSION_DENIED"}}"#;
+        let err = TokenErr::from_response(403, body);
+
+        assert_eq!(err.error(), "PERMISSION_DENIED");
+        assert_eq!(err.error_description(), "Permission denied");
+        assert!(err.is_auth_misconfiguration());
+    }
+
+    #[test]
+    fn non_json_error() {
+        let err = TokenErr::from_response(502, "<html>Bad Gateway</html>");
+
+        assert_eq!(err.error(), "");
+        assert_eq!(err.body(), "<html>Bad Gateway</html>");
+        assert!(err.is_retryable());
+        assert!(!err.is_auth_misconfiguration());
+        assert_eq!(err.to_string(), "TokenErr: 502 <html>Bad Gateway</html>");
+    }
+}
@@ -0,0 +1,505 @@
+//! AWS sourced subject tokens for external accounts (`environment_id` `aws1`).
+//!
+//! The subject token is a serialized, SigV4 signed `GetCallerIdentity` request
+//! which the Security Token Service replays against AWS to verify the caller.
+//! Region and security credentials come from the environment if set, otherwise
+//! from the EC2 instance metadata service.
+
+use crate::http::{HttpClient, HttpRequest, HttpResponse};
+use crate::{GoErr, Result};
+
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use time::OffsetDateTime;
+use url::Url;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SERVICE: &str = "sts";
+const SUPPORTED_VERSION: &str = "1";
+const IMDSV2_TOKEN_TTL_HEADER: &str = "X-aws-ec2-metadata-token-ttl-seconds";
+const IMDSV2_TOKEN_HEADER: &str = "X-aws-ec2-metadata-token";
+const IMDSV2_TOKEN_TTL_SECONDS: &str = "300";
+
+/// Characters left as is when url encoding the subject token, the same set
+/// Python's `urllib.parse.quote` keeps
+const SUBJECT_TOKEN_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Characters left as is in canonical query strings, RFC 3986 unreserved
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// The AWS specific fields of `credential_source`
+pub(crate) struct AwsCredentialSource<'a> {
+    pub environment_id: &'a str,
+    pub region_url: Option<&'a str>,
+    pub url: Option<&'a str>,
+    pub regional_cred_verification_url: Option<&'a str>,
+    pub imdsv2_session_token_url: Option<&'a str>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct AwsSecurityCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+impl AwsSecurityCredentials {
+    fn from_env() -> Option<AwsSecurityCredentials> {
+        Some(AwsSecurityCredentials {
+            access_key_id: env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+fn region_from_env() -> Option<String> {
+    env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .ok()
+}
+
+impl AwsCredentialSource<'_> {
+    /// Builds the subject token for `audience`, the workload identity provider,
+    /// sending metadata service requests through `client`
+    pub(crate) fn subject_token(&self, audience: &str, client: &dyn HttpClient) -> Result<String> {
+        self.subject_token_with(
+            audience,
+            region_from_env(),
+            AwsSecurityCredentials::from_env(),
+            OffsetDateTime::now_utc(),
+            client,
+        )
+    }
+
+    fn subject_token_with(
+        &self,
+        audience: &str,
+        region: Option<String>,
+        credentials: Option<AwsSecurityCredentials>,
+        now: OffsetDateTime,
+        client: &dyn HttpClient,
+    ) -> Result<String> {
+        let version = self.environment_id.trim_start_matches("aws");
+        if !self.environment_id.starts_with("aws") || version != SUPPORTED_VERSION {
+            return Err(GoErr::from(
+                format!("unsupported environment_id `{}`", self.environment_id).as_str(),
+            ));
+        }
+        let verification_url = self.regional_cred_verification_url.ok_or_else(|| {
+            GoErr::from("credential_source is missing `regional_cred_verification_url`")
+        })?;
+
+        // Only talk to the metadata service if the environment doesn't have
+        // everything we need
+        let session_token = match (&region, &credentials) {
+            (Some(_), Some(_)) => None,
+            _ => self.imdsv2_session_token(client)?,
+        };
+        let region = match region {
+            Some(region) => region,
+            None => self.region(client, session_token.as_deref())?,
+        };
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => self.security_credentials(client, session_token.as_deref())?,
+        };
+
+        let url = verification_url.replace("{region}", &region);
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            "x-goog-cloud-target-resource".to_string(),
+            audience.to_string(),
+        );
+        let headers = sign(
+            "POST",
+            &url,
+            headers,
+            "",
+            &credentials,
+            &region,
+            SERVICE,
+            now,
+        )?;
+
+        let request = serde_json::json!({
+            "url": url,
+            "method": "POST",
+            "headers": headers
+                .iter()
+                .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                .collect::<Vec<serde_json::Value>>(),
+            "body": "",
+        });
+        Ok(utf8_percent_encode(&request.to_string(), SUBJECT_TOKEN_ENCODE_SET).to_string())
+    }
+
+    /// Fetches an IMDSv2 session token if the configuration asks for one
+    fn imdsv2_session_token(&self, client: &dyn HttpClient) -> Result<Option<String>> {
+        match self.imdsv2_session_token_url {
+            Some(url) => {
+                let request =
+                    HttpRequest::put(url).header(IMDSV2_TOKEN_TTL_HEADER, IMDSV2_TOKEN_TTL_SECONDS);
+                let response = client.send(request)?;
+                Ok(Some(metadata_response(response, "IMDSv2 session token")?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn region(&self, client: &dyn HttpClient, session_token: Option<&str>) -> Result<String> {
+        let url = self
+            .region_url
+            .ok_or_else(|| GoErr::from("credential_source is missing `region_url`"))?;
+        let availability_zone = metadata_get(client, url, session_token, "region")?;
+        // The availability zone is the region with a zone letter appended,
+        // e.g. `us-east-1b`
+        let mut region = availability_zone.trim().to_string();
+        region.pop();
+        Ok(region)
+    }
+
+    fn security_credentials(
+        &self,
+        client: &dyn HttpClient,
+        session_token: Option<&str>,
+    ) -> Result<AwsSecurityCredentials> {
+        let url = self
+            .url
+            .ok_or_else(|| GoErr::from("credential_source is missing `url`"))?;
+        let role = metadata_get(client, url, session_token, "role name")?;
+        let credentials = metadata_get(
+            client,
+            &format!("{}/{}", url.trim_end_matches('/'), role.trim()),
+            session_token,
+            "security credentials",
+        )?;
+        Ok(serde_json::from_str(&credentials)?)
+    }
+}
+
+fn metadata_get(
+    client: &dyn HttpClient,
+    url: &str,
+    session_token: Option<&str>,
+    what: &str,
+) -> Result<String> {
+    let mut request = HttpRequest::get(url);
+    if let Some(session_token) = session_token {
+        request = request.header(IMDSV2_TOKEN_HEADER, session_token);
+    }
+    metadata_response(client.send(request)?, what)
+}
+
+fn metadata_response(response: HttpResponse, what: &str) -> Result<String> {
+    if response.is_success() {
+        Ok(response.text())
+    } else {
+        Err(GoErr::from(
+            format!(
+                "failed to fetch AWS {}, metadata service returned {}: {}",
+                what,
+                response.status(),
+                response.text()
+            )
+            .as_str(),
+        ))
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &str) -> String {
+    hex::encode(Sha256::digest(data.as_bytes()))
+}
+
+/// Signs a request with AWS Signature Version 4, returning the request
+/// headers, including `host`, `x-amz-date` and `Authorization`.
+#[allow(clippy::too_many_arguments)]
+fn sign(
+    method: &str,
+    url: &str,
+    mut headers: BTreeMap<String, String>,
+    body: &str,
+    credentials: &AwsSecurityCredentials,
+    region: &str,
+    service: &str,
+    now: OffsetDateTime,
+) -> Result<BTreeMap<String, String>> {
+    let parsed = Url::parse(url)
+        .map_err(|e| GoErr::from(format!("invalid AWS url `{}`: {}", url, e).as_str()))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| GoErr::from(format!("AWS url `{}` has no host", url).as_str()))?;
+
+    let amz_date = format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    let date_stamp = &amz_date[..8];
+
+    headers.insert("host".to_string(), host.to_string());
+    headers.insert("x-amz-date".to_string(), amz_date.clone());
+    if let Some(token) = &credentials.token {
+        headers.insert("x-amz-security-token".to_string(), token.clone());
+    }
+
+    let canonical_uri = match parsed.path() {
+        "" => "/",
+        path => path,
+    };
+    let mut query = parsed
+        .query_pairs()
+        .map(|(key, value)| {
+            (
+                utf8_percent_encode(&key, QUERY_ENCODE_SET).to_string(),
+                utf8_percent_encode(&value, QUERY_ENCODE_SET).to_string(),
+            )
+        })
+        .collect::<Vec<(String, String)>>();
+    query.sort();
+    let canonical_query = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>()
+        .join("&");
+    // BTreeMap keeps the lowercase header names sorted as SigV4 requires
+    let canonical_headers = headers
+        .iter()
+        .map(|(key, value)| format!("{}:{}\n", key.to_lowercase(), value.trim()))
+        .collect::<String>();
+    let signed_headers = headers
+        .keys()
+        .map(|key| key.to_lowercase())
+        .collect::<Vec<String>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        canonical_uri,
+        canonical_query,
+        canonical_headers,
+        signed_headers,
+        sha256_hex(body)
+    );
+    let credential_scope = format!("{}/{}/{}/aws4_request", date_stamp, region, service);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        credential_scope,
+        sha256_hex(&canonical_request)
+    );
+
+    let key = format!("AWS4{}", credentials.secret_access_key);
+    let key = hmac_sha256(key.as_bytes(), date_stamp);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    let key = hmac_sha256(&key, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+    headers.insert(
+        "Authorization".to_string(),
+        format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, credentials.access_key_id, credential_scope, signed_headers, signature
+        ),
+    );
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::AttoHttpClient;
+    use mockito::{self, mock};
+    use percent_encoding::percent_decode_str;
+
+    fn example_credentials(token: Option<&str>) -> AwsSecurityCredentials {
+        AwsSecurityCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            token: token.map(|token| token.to_string()),
+        }
+    }
+
+    fn source<'a>(base: &'a str, verification_url: &'a str) -> AwsCredentialSource<'a> {
+        AwsCredentialSource {
+            environment_id: "aws1",
+            region_url: Some(base),
+            url: Some(base),
+            regional_cred_verification_url: Some(verification_url),
+            imdsv2_session_token_url: None,
+        }
+    }
+
+    /// `get-vanilla` from the AWS SigV4 test suite
+    #[test]
+    fn sigv4_test_suite_vector() {
+        let headers = sign(
+            "GET",
+            "https://example.amazonaws.com/",
+            BTreeMap::new(),
+            "",
+            &example_credentials(None),
+            "us-east-1",
+            "service",
+            OffsetDateTime::from_unix_timestamp(1440938160).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            headers["Authorization"],
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        assert_eq!(headers["x-amz-date"], "20150830T123600Z");
+    }
+
+    #[test]
+    fn subject_token_from_env() {
+        let source = source(
+            "http://unused",
+            "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+        );
+        let token = source
+            .subject_token_with(
+                "//iam.googleapis.com/audience",
+                Some("us-east-2".to_string()),
+                Some(example_credentials(Some("session"))),
+                OffsetDateTime::from_unix_timestamp(1597128922).unwrap(),
+                &AttoHttpClient,
+            )
+            .unwrap();
+
+        let decoded = percent_decode_str(&token).decode_utf8().unwrap();
+        let request: serde_json::Value = serde_json::from_str(&decoded).unwrap();
+        assert_eq!(
+            request["url"],
+            "https://sts.us-east-2.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15"
+        );
+        assert_eq!(request["method"], "POST");
+        assert_eq!(request["body"], "");
+
+        let keys = request["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|header| header["key"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            keys,
+            vec![
+                "Authorization",
+                "host",
+                "x-amz-date",
+                "x-amz-security-token",
+                "x-goog-cloud-target-resource"
+            ]
+        );
+        assert!(request["headers"][0]["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20200811/us-east-2/sts/aws4_request, SignedHeaders=host;x-amz-date;x-amz-security-token;x-goog-cloud-target-resource, Signature="));
+    }
+
+    #[test]
+    fn subject_token_from_metadata_with_imdsv2() {
+        let session = mock("PUT", "/latest/api/token")
+            .match_header("X-aws-ec2-metadata-token-ttl-seconds", "300")
+            .with_status(200)
+            .with_body("imdsv2")
+            .create();
+        let region = mock("GET", "/latest/meta-data/placement/availability-zone")
+            .match_header("X-aws-ec2-metadata-token", "imdsv2")
+            .with_status(200)
+            .with_body("us-east-1b")
+            .create();
+        let role = mock("GET", "/latest/meta-data/iam/security-credentials")
+            .match_header("X-aws-ec2-metadata-token", "imdsv2")
+            .with_status(200)
+            .with_body("role")
+            .create();
+        let credentials = mock("GET", "/latest/meta-data/iam/security-credentials/role")
+            .match_header("X-aws-ec2-metadata-token", "imdsv2")
+            .with_status(200)
+            .with_body(r#"{"Code":"Success","AccessKeyId":"AKID","SecretAccessKey":"secret","Token":"token"}"#)
+            .create();
+
+        let region_url = format!(
+            "{}/latest/meta-data/placement/availability-zone",
+            mockito::server_url()
+        );
+        let url = format!(
+            "{}/latest/meta-data/iam/security-credentials",
+            mockito::server_url()
+        );
+        let session_url = format!("{}/latest/api/token", mockito::server_url());
+        let source = AwsCredentialSource {
+            environment_id: "aws1",
+            region_url: Some(&region_url),
+            url: Some(&url),
+            regional_cred_verification_url: Some(
+                "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+            ),
+            imdsv2_session_token_url: Some(&session_url),
+        };
+
+        let token = source
+            .subject_token_with(
+                "//iam.googleapis.com/audience",
+                None,
+                None,
+                OffsetDateTime::now_utc(),
+                &AttoHttpClient,
+            )
+            .unwrap();
+        let decoded = percent_decode_str(&token).decode_utf8().unwrap();
+        assert!(decoded.contains("https://sts.us-east-1.amazonaws.com"));
+        assert!(decoded.contains("AKID/"));
+
+        session.assert();
+        region.assert();
+        role.assert();
+        credentials.assert();
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut source = source("http://unused", "https://sts.{region}.amazonaws.com");
+        source.environment_id = "aws2";
+
+        assert!(source
+            .subject_token_with(
+                "audience",
+                Some("us-east-1".to_string()),
+                Some(example_credentials(None)),
+                OffsetDateTime::now_utc(),
+                &AttoHttpClient,
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Domain-wide delegation, where a service account acts on behalf of the users
+//! of a Workspace domain by setting the `sub` claim of its JWTs.
+//!
+//! `DelegatedFetchers` keeps one `TokenFetcher` per delegated user, so each
+//! user's token is cached independently, and drops the fetchers of users that
+//! have not been seen within an idle timeout.
+
+use crate::auth::{JwtClaims, Token};
+use crate::credentials::Credentials;
+use crate::fetcher::TokenFetcher;
+use crate::http::{default_client, HttpClient};
+use crate::scopes::Scope;
+use crate::Result;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use time::{Duration, OffsetDateTime};
+
+/// How long a user's fetcher is kept after it was last used, by default
+const DEFAULT_IDLE_TIMEOUT_SECONDS: i64 = 3600;
+
+struct Entry {
+    fetcher: Arc<TokenFetcher>,
+    last_used: OffsetDateTime,
+}
+
+/// A pool of `TokenFetcher`s for a service account with domain-wide
+/// delegation, keyed on the email of the delegated user.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::credentials::Credentials;
+/// use goauth::delegation::DelegatedFetchers;
+/// use goauth::scopes::Scope;
+/// use time::Duration;
+///
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let fetchers = DelegatedFetchers::new(credentials, &[Scope::GmailReadOnly], Duration::new(60, 0));
+/// let token = fetchers.fetch_token("user@example.com").unwrap();
+/// ```
+pub struct DelegatedFetchers {
+    credentials: Credentials,
+    scopes: Vec<Scope>,
+    refresh_buffer: Duration,
+    idle_timeout: Duration,
+    http_client: Arc<dyn HttpClient>,
+    fetchers: Mutex<HashMap<String, Entry>>,
+}
+
+impl DelegatedFetchers {
+    pub fn new(
+        credentials: Credentials,
+        scopes: &[Scope],
+        refresh_buffer: Duration,
+    ) -> DelegatedFetchers {
+        DelegatedFetchers {
+            credentials,
+            scopes: scopes.to_vec(),
+            refresh_buffer,
+            idle_timeout: Duration::new(DEFAULT_IDLE_TIMEOUT_SECONDS, 0),
+            http_client: default_client(),
+            fetchers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drop a user's fetcher, and its cached token, once it has not been used
+    /// for `idle_timeout`
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> DelegatedFetchers {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sends the token requests of every user's fetcher through `client`
+    /// instead of the default `attohttpc` transport
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> DelegatedFetchers {
+        self.http_client = client;
+        self
+    }
+
+    /// Returns a token for `user`, cached by that user's `TokenFetcher`
+    pub fn fetch_token(&self, user: &str) -> Result<Token> {
+        self.fetcher(user)?.fetch_token()
+    }
+
+    /// Returns the `TokenFetcher` of `user`, creating it on first use. Fetchers
+    /// of users that have been idle for longer than the idle timeout are evicted.
+    pub fn fetcher(&self, user: &str) -> Result<Arc<TokenFetcher>> {
+        let now = OffsetDateTime::now_utc();
+        let mut fetchers = self.fetchers.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let idle_timeout = self.idle_timeout;
+        fetchers.retain(|_, entry| now - entry.last_used < idle_timeout);
+
+        if let Some(entry) = fetchers.get_mut(user) {
+            entry.last_used = now;
+            return Ok(entry.fetcher.clone());
+        }
+
+        let fetcher = Arc::new(self.new_fetcher(user));
+        fetchers.insert(
+            user.to_string(),
+            Entry {
+                fetcher: fetcher.clone(),
+                last_used: now,
+            },
+        );
+        Ok(fetcher)
+    }
+
+    /// The number of users that currently have a fetcher
+    pub fn len(&self) -> usize {
+        self.fetchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn new_fetcher(&self, user: &str) -> TokenFetcher {
+        let claims = JwtClaims::new(
+            self.credentials.iss(),
+            &self.scopes,
+            self.credentials.token_uri(),
+            None,
+            None,
+        )
+        .with_subject(user);
+        TokenFetcher::with_service_account(self.credentials.clone(), claims, self.refresh_buffer)
+            .with_http_client(self.http_client.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{HttpRequest, HttpResponse};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use std::thread;
+    use std::time::Duration as StdDuration;
+
+    /// Answers each token request with a token for the `sub` claim of its
+    /// assertion, `alice@example.com` getting `alice_token`
+    #[derive(Default)]
+    struct SubjectClient {
+        subjects: Mutex<Vec<String>>,
+    }
+
+    impl HttpClient for SubjectClient {
+        fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+            let assertion = url::form_urlencoded::parse(request.body())
+                .find(|(name, _)| name == "assertion")
+                .map(|(_, value)| value.into_owned())
+                .unwrap();
+            let claims = assertion.split('.').nth(1).unwrap();
+            let claims: serde_json::Value = serde_json::from_slice(
+                &URL_SAFE_NO_PAD
+                    .decode(claims.trim_end_matches('='))
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(claims["scope"], Scope::GmailReadOnly.url());
+
+            let sub = claims["sub"].as_str().unwrap().to_string();
+            let user = sub.split('@').next().unwrap();
+            let body = serde_json::json!({
+                "access_token": format!("{}_token", user),
+                "token_type": "Bearer",
+                "expires_in": 3600
+            });
+            self.subjects.lock().unwrap().push(sub);
+            Ok(HttpResponse::new(200, body.to_string().into_bytes()))
+        }
+    }
+
+    fn fetchers() -> DelegatedFetchers {
+        let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        DelegatedFetchers::new(credentials, &[Scope::GmailReadOnly], Duration::new(60, 0))
+    }
+
+    #[test]
+    fn one_fetcher_per_user() {
+        let client = Arc::new(SubjectClient::default());
+        let fetchers = fetchers().with_http_client(client.clone());
+
+        let alice = fetchers.fetcher("alice@example.com").unwrap();
+        let again = fetchers.fetcher("alice@example.com").unwrap();
+        let bob = fetchers.fetcher("bob@example.com").unwrap();
+
+        assert!(Arc::ptr_eq(&alice, &again));
+        assert!(!Arc::ptr_eq(&alice, &bob));
+        assert_eq!(fetchers.len(), 2);
+        assert_eq!(
+            fetchers
+                .fetch_token("alice@example.com")
+                .unwrap()
+                .access_token(),
+            "alice_token"
+        );
+        assert_eq!(
+            fetchers
+                .fetch_token("bob@example.com")
+                .unwrap()
+                .access_token(),
+            "bob_token"
+        );
+        assert_eq!(
+            *client.subjects.lock().unwrap(),
+            vec!["alice@example.com", "bob@example.com"]
+        );
+    }
+
+    #[test]
+    fn idle_users_are_evicted() {
+        let fetchers = fetchers().with_idle_timeout(Duration::new(1, 0));
+
+        let alice = fetchers.fetcher("alice@example.com").unwrap();
+        thread::sleep(StdDuration::from_millis(1100));
+        let bob = fetchers.fetcher("bob@example.com").unwrap();
+
+        assert_eq!(fetchers.len(), 1);
+        assert!(!Arc::ptr_eq(
+            &alice,
+            &fetchers.fetcher("alice@example.com").unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            &bob,
+            &fetchers.fetcher("bob@example.com").unwrap()
+        ));
+    }
+}
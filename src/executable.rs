@@ -0,0 +1,359 @@
+//! Executable sourced subject tokens for external accounts, a local program is
+//! run and prints the subject token (an OIDC ID token or a SAML response) to
+//! stdout in a documented JSON format.
+//!
+//! Running arbitrary programs is opt-in, `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES`
+//! has to be set to `1`.
+
+use crate::http::TimeoutErr;
+use crate::{GoErr, Result};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+pub const ALLOW_EXECUTABLES_ENV_VAR: &str = "GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES";
+
+const DEFAULT_TIMEOUT_MILLIS: u64 = 30_000;
+const MIN_TIMEOUT_MILLIS: u64 = 5_000;
+const MAX_TIMEOUT_MILLIS: u64 = 120_000;
+const SUPPORTED_VERSION: u32 = 1;
+
+const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+const SAML2_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:saml2";
+
+/// `credential_source.executable` of an external account configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecutableConfig {
+    command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout_millis: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_file: Option<String>,
+}
+
+/// What the executable prints to stdout, and what is cached in `output_file`
+#[derive(Deserialize, Debug)]
+struct ExecutableResponse {
+    version: u32,
+    success: bool,
+    #[serde(default)]
+    token_type: Option<String>,
+    #[serde(default)]
+    id_token: Option<String>,
+    #[serde(default)]
+    saml_response: Option<String>,
+    #[serde(default)]
+    expiration_time: Option<i64>,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl ExecutableResponse {
+    fn is_expired(&self) -> bool {
+        match self.expiration_time {
+            Some(expiration_time) => expiration_time <= OffsetDateTime::now_utc().unix_timestamp(),
+            None => false,
+        }
+    }
+
+    /// Validates the response and pulls out the subject token
+    fn subject_token(self, requires_expiration: bool) -> Result<String> {
+        if self.version > SUPPORTED_VERSION {
+            return Err(GoErr::from(
+                format!("unsupported executable response version {}", self.version).as_str(),
+            ));
+        }
+        if !self.success {
+            return Err(GoErr::from(
+                format!(
+                    "executable failed with code {}: {}",
+                    self.code.as_deref().unwrap_or("unknown"),
+                    self.message.as_deref().unwrap_or("no message")
+                )
+                .as_str(),
+            ));
+        }
+        if requires_expiration && self.expiration_time.is_none() {
+            return Err(GoErr::from(
+                "executable response must include `expiration_time` when `output_file` is set",
+            ));
+        }
+        if self.is_expired() {
+            return Err(GoErr::from("executable returned an expired subject token"));
+        }
+
+        let token = match self.token_type.as_deref() {
+            Some(ID_TOKEN_TYPE) | Some(JWT_TOKEN_TYPE) => self.id_token,
+            Some(SAML2_TOKEN_TYPE) => self.saml_response,
+            Some(t) => {
+                return Err(GoErr::from(
+                    format!("unsupported executable token type `{}`", t).as_str(),
+                ))
+            }
+            None => return Err(GoErr::from("executable response has no `token_type`")),
+        };
+        token.ok_or_else(|| GoErr::from("executable response is missing the subject token"))
+    }
+}
+
+/// Environment the executable is run with
+pub(crate) struct ExecutableEnv<'a> {
+    pub audience: &'a str,
+    pub subject_token_type: &'a str,
+    pub impersonated_email: Option<&'a str>,
+}
+
+impl ExecutableConfig {
+    fn timeout(&self) -> Result<Duration> {
+        let millis = self.timeout_millis.unwrap_or(DEFAULT_TIMEOUT_MILLIS);
+        if !(MIN_TIMEOUT_MILLIS..=MAX_TIMEOUT_MILLIS).contains(&millis) {
+            return Err(GoErr::from(
+                format!(
+                    "executable timeout_millis must be between {} and {}",
+                    MIN_TIMEOUT_MILLIS, MAX_TIMEOUT_MILLIS
+                )
+                .as_str(),
+            ));
+        }
+        Ok(Duration::from_millis(millis))
+    }
+
+    /// Returns the subject token, from `output_file` if it holds an unexpired
+    /// one, otherwise by running the executable
+    pub(crate) fn subject_token(&self, env: &ExecutableEnv) -> Result<String> {
+        if !executables_allowed(env::var(ALLOW_EXECUTABLES_ENV_VAR).ok().as_deref()) {
+            return Err(GoErr::from(
+                format!(
+                    "executables need to be explicitly allowed (set {} to '1') to run",
+                    ALLOW_EXECUTABLES_ENV_VAR
+                )
+                .as_str(),
+            ));
+        }
+        self.run_or_read_cached(env, self.timeout()?)
+    }
+
+    fn run_or_read_cached(&self, env: &ExecutableEnv, timeout: Duration) -> Result<String> {
+        if let Some(output_file) = &self.output_file {
+            if let Some(response) = read_output_file(output_file) {
+                if !response.is_expired() {
+                    return response.subject_token(true);
+                }
+            }
+        }
+
+        let output = self.run(env, timeout)?;
+        let response: ExecutableResponse = serde_json::from_str(&output)
+            .map_err(|e| GoErr::from(format!("invalid executable response: {}", e).as_str()))?;
+        response.subject_token(self.output_file.is_some())
+    }
+
+    /// Runs the command, returning its stdout, killing it after `timeout`
+    fn run(&self, env: &ExecutableEnv, timeout: Duration) -> Result<String> {
+        let mut args = self.command.split_whitespace();
+        let program = args
+            .next()
+            .ok_or_else(|| GoErr::from("executable command is empty"))?;
+
+        let mut vars = HashMap::new();
+        vars.insert("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", env.audience);
+        vars.insert("GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE", env.subject_token_type);
+        vars.insert("GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE", "0");
+        if let Some(email) = env.impersonated_email {
+            vars.insert("GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL", email);
+        }
+        if let Some(output_file) = &self.output_file {
+            vars.insert("GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE", output_file);
+        }
+
+        let mut child = Command::new(program)
+            .args(args)
+            .envs(vars)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Read stdout on a separate thread so a chatty executable can't block
+        // on a full pipe while we wait for it
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let reader = thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(GoErr::from(TimeoutErr::new(&format!(
+                    "executable `{}`, limited to {}ms,",
+                    program,
+                    timeout.as_millis()
+                ))));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let output = reader
+            .join()
+            .map_err(|_| GoErr::from("failed to read executable output"))??;
+
+        if !status.success() {
+            return Err(GoErr::from(
+                format!("executable exited with {}: {}", status, output.trim()).as_str(),
+            ));
+        }
+        Ok(output)
+    }
+}
+
+fn executables_allowed(allow: Option<&str>) -> bool {
+    allow == Some("1")
+}
+
+/// A cached response, ignored if missing or unreadable
+fn read_output_file(output_file: &str) -> Option<ExecutableResponse> {
+    let contents = fs::read_to_string(output_file).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const ENV: ExecutableEnv = ExecutableEnv {
+        audience: "//iam.googleapis.com/audience",
+        subject_token_type: JWT_TOKEN_TYPE,
+        impersonated_email: Some("sa@project.iam.gserviceaccount.com"),
+    };
+
+    fn script(name: &str, body: &str) -> String {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn config(command: String, output_file: Option<String>) -> ExecutableConfig {
+        ExecutableConfig {
+            command,
+            timeout_millis: None,
+            output_file,
+        }
+    }
+
+    #[test]
+    fn executables_must_be_allowed() {
+        assert!(executables_allowed(Some("1")));
+        assert!(!executables_allowed(Some("true")));
+        assert!(!executables_allowed(None));
+    }
+
+    #[test]
+    fn runs_with_environment() {
+        let command = script(
+            "goauth_executable_env.sh",
+            r#"echo "{\"version\":1,\"success\":true,\"token_type\":\"$GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE\",\"id_token\":\"$GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL $1\"}""#,
+        );
+
+        let token = config(format!("{} arg", command), None)
+            .run_or_read_cached(&ENV, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(token, "sa@project.iam.gserviceaccount.com arg");
+    }
+
+    #[test]
+    fn saml_response() {
+        let command = script(
+            "goauth_executable_saml.sh",
+            r#"echo '{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:saml2","saml_response":"saml","expiration_time":4102444800}'"#,
+        );
+
+        let token = config(command, None)
+            .run_or_read_cached(&ENV, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(token, "saml");
+    }
+
+    #[test]
+    fn unsuccessful_response() {
+        let command = script(
+            "goauth_executable_failure.sh",
+            r#"echo '{"version":1,"success":false,"code":"401","message":"Permission denied"}'"#,
+        );
+
+        let err = config(command, None)
+            .run_or_read_cached(&ENV, Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "executable failed with code 401: Permission denied"
+        );
+    }
+
+    #[test]
+    fn uses_unexpired_output_file() {
+        let output_file = env::temp_dir().join("goauth_executable_output.json");
+        fs::write(
+            &output_file,
+            r#"{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:id_token","id_token":"cached","expiration_time":4102444800}"#,
+        )
+        .unwrap();
+        // Would fail if it was run
+        let command = script("goauth_executable_not_run.sh", "exit 1");
+
+        let token = config(command, Some(output_file.to_str().unwrap().to_string()))
+            .run_or_read_cached(&ENV, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(token, "cached");
+    }
+
+    #[test]
+    fn output_file_requires_expiration() {
+        let command = script(
+            "goauth_executable_no_expiration.sh",
+            r#"echo '{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:jwt","id_token":"token"}'"#,
+        );
+        let output_file = env::temp_dir().join("goauth_executable_missing_output.json");
+        let _ = fs::remove_file(&output_file);
+
+        let result = config(command, Some(output_file.to_str().unwrap().to_string()))
+            .run_or_read_cached(&ENV, Duration::from_secs(5));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn times_out() {
+        let command = script("goauth_executable_slow.sh", "sleep 5");
+
+        let started = Instant::now();
+        let err = config(command, None)
+            .run_or_read_cached(&ENV, Duration::from_millis(100))
+            .unwrap_err();
+        assert!(err.is_timeout());
+        assert!(err.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn timeout_out_of_range() {
+        let mut config = config("true".to_string(), None);
+        config.timeout_millis = Some(1_000);
+        assert!(config.timeout().is_err());
+    }
+}
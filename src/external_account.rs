@@ -0,0 +1,459 @@
+//! Workload Identity Federation, credentials of type `external_account`.
+//!
+//! A subject token issued by a third party identity provider is exchanged at
+//! the Security Token Service for a Google access token, which is optionally
+//! exchanged once more for a token of a service account via impersonation.
+
+use crate::auth::Token;
+use crate::aws::AwsCredentialSource;
+use crate::credentials::CredentialsType;
+use crate::executable::{ExecutableConfig, ExecutableEnv};
+use crate::http::{AttoHttpClient, HttpClient, HttpRequest};
+use crate::impersonate::{
+    generate_access_token_request, generate_access_token_response, DEFAULT_LIFETIME_SECONDS,
+};
+use crate::scopes::Scope;
+use crate::source::TokenSource;
+use crate::{token_response, GoErr, Result};
+
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+pub use crate::executable::ALLOW_EXECUTABLES_ENV_VAR;
+
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Where and how to read the subject token from
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CredentialSource {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    headers: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<CredentialSourceFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    executable: Option<ExecutableConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    environment_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    region_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    regional_cred_verification_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imdsv2_session_token_url: Option<String>,
+}
+
+/// Format of a file or URL sourced subject token, either `text` or `json`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CredentialSourceFormat {
+    #[serde(rename = "type")]
+    t: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subject_token_field_name: Option<String>,
+}
+
+impl CredentialSourceFormat {
+    /// Extracts the subject token out of the raw file or response contents
+    fn parse(&self, contents: &str) -> Result<String> {
+        match self.t.as_str() {
+            "text" => Ok(contents.trim().to_string()),
+            "json" => {
+                let field = self.subject_token_field_name.as_deref().ok_or_else(|| {
+                    GoErr::from(
+                        "`subject_token_field_name` is required for json credential sources",
+                    )
+                })?;
+                let value: serde_json::Value = serde_json::from_str(contents)?;
+                match value.get(field) {
+                    Some(serde_json::Value::String(token)) => Ok(token.clone()),
+                    _ => Err(GoErr::from(
+                        format!("subject token field `{}` is missing or not a string", field)
+                            .as_str(),
+                    )),
+                }
+            }
+            t => Err(GoErr::from(
+                format!("unsupported credential source format `{}`", t).as_str(),
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceAccountImpersonation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_lifetime_seconds: Option<u32>,
+}
+
+/// Credentials of type `external_account`, as generated by
+/// `gcloud iam workload-identity-pools create-cred-config`.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::external_account::ExternalAccountCredentials;
+/// use goauth::fetcher::TokenFetcher;
+/// use goauth::scopes::Scope;
+/// use time::Duration;
+///
+/// let credentials = ExternalAccountCredentials::from_file("external_account.json")
+///     .unwrap()
+///     .with_scopes(&[Scope::DevStorageReadWrite]);
+/// let fetcher = TokenFetcher::with_external_account(credentials, Duration::new(60, 0));
+/// let token = fetcher.fetch_token().unwrap();
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalAccountCredentials {
+    #[serde(rename = "type")]
+    t: String,
+    audience: String,
+    subject_token_type: String,
+    token_url: String,
+    credential_source: CredentialSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    service_account_impersonation_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    service_account_impersonation: Option<ServiceAccountImpersonation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota_project_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    workforce_pool_user_project: Option<String>,
+    #[serde(skip)]
+    scopes: Vec<String>,
+}
+
+impl ExternalAccountCredentials {
+    pub fn from_file(fp: &str) -> Result<Self> {
+        ExternalAccountCredentials::from_str(&fs::read_to_string(fp)?)
+    }
+
+    /// Scopes of the resulting access token, `cloud-platform` if none are set
+    pub fn with_scopes(mut self, scopes: &[Scope]) -> Self {
+        self.scopes = scopes.iter().map(|scope| scope.url()).collect();
+        self
+    }
+
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+
+    fn scopes(&self) -> Vec<String> {
+        if self.scopes.is_empty() {
+            vec![CLOUD_PLATFORM_SCOPE.to_string()]
+        } else {
+            self.scopes.clone()
+        }
+    }
+
+    /// The service account being impersonated, taken from the impersonation url
+    fn impersonated_email(&self) -> Option<&str> {
+        let url = self.service_account_impersonation_url.as_deref()?;
+        let email = url.rsplit('/').next()?;
+        Some(email.trim_end_matches(":generateAccessToken"))
+    }
+
+    /// Reads the subject token from the configured credential source
+    pub fn subject_token(&self) -> Result<String> {
+        self.subject_token_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `subject_token`, sending url and AWS metadata requests through `client`
+    pub fn subject_token_with_http_client(&self, client: &dyn HttpClient) -> Result<String> {
+        let source = &self.credential_source;
+        if let Some(executable) = &source.executable {
+            return executable.subject_token(&ExecutableEnv {
+                audience: &self.audience,
+                subject_token_type: &self.subject_token_type,
+                impersonated_email: self.impersonated_email(),
+            });
+        }
+        if let Some(environment_id) = &source.environment_id {
+            let aws = AwsCredentialSource {
+                environment_id,
+                region_url: source.region_url.as_deref(),
+                url: source.url.as_deref(),
+                regional_cred_verification_url: source.regional_cred_verification_url.as_deref(),
+                imdsv2_session_token_url: source.imdsv2_session_token_url.as_deref(),
+            };
+            return aws.subject_token(&self.audience, client);
+        }
+
+        let contents = if let Some(file) = &source.file {
+            fs::read_to_string(file)?
+        } else if let Some(url) = &source.url {
+            let mut request = HttpRequest::get(url);
+            for (name, value) in source.headers.iter().flatten() {
+                request = request.header(name, value);
+            }
+            let response = client.send(request)?;
+            if !response.is_success() {
+                return Err(GoErr::from(
+                    format!(
+                        "subject token url returned {}: {}",
+                        response.status(),
+                        response.text()
+                    )
+                    .as_str(),
+                ));
+            }
+            response.text()
+        } else {
+            return Err(GoErr::from(
+                "credential_source must specify a `file`, a `url` or an `executable`",
+            ));
+        };
+
+        match &source.format {
+            Some(format) => format.parse(&contents),
+            None => Ok(contents.trim().to_string()),
+        }
+    }
+
+    /// The exchange of the subject token at the Security Token Service
+    pub(crate) fn exchange_request(&self, subject_token: &str) -> HttpRequest {
+        // When impersonating, the STS token only needs to be able to call the
+        // IAM Credentials API, the final token carries the requested scopes
+        let scope = if self.service_account_impersonation_url.is_some() {
+            CLOUD_PLATFORM_SCOPE.to_string()
+        } else {
+            self.scopes().join(" ")
+        };
+        let options = match (&self.workforce_pool_user_project, &self.client_id) {
+            (Some(project), None) => {
+                Some(serde_json::json!({ "userProject": project }).to_string())
+            }
+            _ => None,
+        };
+
+        let mut request_body = vec![
+            ("grant_type", TOKEN_EXCHANGE_GRANT_TYPE),
+            ("audience", self.audience.as_str()),
+            ("scope", scope.as_str()),
+            ("requested_token_type", ACCESS_TOKEN_TYPE),
+            ("subject_token", subject_token),
+            ("subject_token_type", self.subject_token_type.as_str()),
+        ];
+        if let Some(options) = &options {
+            request_body.push(("options", options.as_str()));
+        }
+
+        let mut request = HttpRequest::post_form(&self.token_url, &request_body);
+        if let Some(client_id) = &self.client_id {
+            request = request.basic_auth(client_id, self.client_secret.as_deref());
+        }
+        request
+    }
+
+    /// The exchange of the STS token for a token of the impersonated service
+    /// account, if there is one
+    pub(crate) fn impersonation_request(&self, sts_token: &Token) -> Result<Option<HttpRequest>> {
+        let url = match &self.service_account_impersonation_url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let lifetime = self
+            .service_account_impersonation
+            .as_ref()
+            .and_then(|impersonation| impersonation.token_lifetime_seconds)
+            .unwrap_or(DEFAULT_LIFETIME_SECONDS);
+
+        let request = generate_access_token_request(url, sts_token, &[], &self.scopes(), lifetime)?;
+        Ok(Some(request))
+    }
+
+    /// Fetches a new access token, reading a fresh subject token each time
+    pub fn get_token(&self) -> Result<Token> {
+        self.get_token_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `get_token`, sending every request, from reading the subject token
+    /// to impersonation, through `client`
+    pub fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        let subject_token = self.subject_token_with_http_client(client)?;
+        let token = token_response(client.send(self.exchange_request(&subject_token))?)?;
+
+        match self.impersonation_request(&token)? {
+            Some(request) => generate_access_token_response(client.send(request)?),
+            None => Ok(token),
+        }
+    }
+}
+
+impl TokenSource for ExternalAccountCredentials {
+    fn get_token(&self) -> Result<Token> {
+        ExternalAccountCredentials::get_token(self)
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        ExternalAccountCredentials::get_token_with_http_client(self, client)
+    }
+}
+
+impl FromStr for ExternalAccountCredentials {
+    type Err = GoErr;
+    fn from_str(s: &str) -> Result<Self, GoErr> {
+        crate::credentials::from_typed_str(s, CredentialsType::ExternalAccount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::tests::ScriptedClient;
+    use mockito::{self, mock, Matcher};
+
+    fn credentials(
+        credential_source: serde_json::Value,
+        impersonate: bool,
+    ) -> ExternalAccountCredentials {
+        let mut config = serde_json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/sts", mockito::server_url()),
+            "credential_source": credential_source,
+        });
+        if impersonate {
+            config["service_account_impersonation_url"] = serde_json::json!(format!(
+                "{}/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken",
+                mockito::server_url()
+            ));
+        }
+        ExternalAccountCredentials::from_str(&config.to_string()).unwrap()
+    }
+
+    fn sts_mock(subject_token: &str) -> mockito::Mock {
+        mock("POST", "/sts")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("grant_type".to_string(), TOKEN_EXCHANGE_GRANT_TYPE.to_string()),
+                Matcher::UrlEncoded("subject_token".to_string(), subject_token.to_string()),
+                Matcher::UrlEncoded(
+                    "subject_token_type".to_string(),
+                    "urn:ietf:params:oauth:token-type:jwt".to_string(),
+                ),
+            ]))
+            .with_status(200)
+            .with_body(
+                r#"{"access_token":"sts_token","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+            )
+            .create()
+    }
+
+    #[test]
+    fn file_sourced_text() {
+        let path = std::env::temp_dir().join("goauth_external_account_text");
+        fs::write(&path, "file_subject_token\n").unwrap();
+
+        let credentials = credentials(serde_json::json!({ "file": path }), false);
+        let sts = sts_mock("file_subject_token");
+
+        let token = credentials.get_token().unwrap();
+        assert_eq!(token.access_token(), "sts_token");
+        sts.assert();
+    }
+
+    #[test]
+    fn file_sourced_json() {
+        let path = std::env::temp_dir().join("goauth_external_account_json");
+        fs::write(&path, r#"{"id_token":"json_subject_token"}"#).unwrap();
+
+        let credentials = credentials(
+            serde_json::json!({
+                "file": path,
+                "format": { "type": "json", "subject_token_field_name": "id_token" }
+            }),
+            false,
+        );
+
+        assert_eq!(credentials.subject_token().unwrap(), "json_subject_token");
+    }
+
+    #[test]
+    fn url_sourced_with_impersonation() {
+        let _subject = mock("GET", "/subject")
+            .match_header("Metadata", "True")
+            .with_status(200)
+            .with_body(r#"{"access_token":"url_subject_token"}"#)
+            .create();
+        let sts = sts_mock("url_subject_token");
+        let impersonation = mock(
+            "POST",
+            "/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken",
+        )
+        .match_header("Authorization", "Bearer sts_token")
+        .match_body(Matcher::PartialJson(serde_json::json!({
+            "scope": [Scope::DevStorageReadWrite.url()],
+            "lifetime": "3600s"
+        })))
+        .with_status(200)
+        .with_body(r#"{"accessToken":"impersonated_token","expireTime":"2099-01-01T00:00:00Z"}"#)
+        .create();
+
+        let credentials = credentials(
+            serde_json::json!({
+                "url": format!("{}/subject", mockito::server_url()),
+                "headers": { "Metadata": "True" },
+                "format": { "type": "json", "subject_token_field_name": "access_token" }
+            }),
+            true,
+        )
+        .with_scopes(&[Scope::DevStorageReadWrite]);
+
+        let token = credentials.get_token().unwrap();
+        assert_eq!(token.access_token(), "impersonated_token");
+        sts.assert();
+        impersonation.assert();
+    }
+
+    #[test]
+    fn every_request_goes_through_the_http_client() {
+        let client = ScriptedClient::new(&[
+            (200, "scripted_subject_token"),
+            (
+                200,
+                r#"{"access_token":"sts_token","token_type":"Bearer","expires_in":3600}"#,
+            ),
+            (
+                200,
+                r#"{"accessToken":"impersonated_token","expireTime":"2099-01-01T00:00:00Z"}"#,
+            ),
+        ]);
+        let credentials = credentials(
+            serde_json::json!({ "url": "http://subject.invalid/token" }),
+            true,
+        );
+
+        let token = credentials.get_token_with_http_client(&client).unwrap();
+        assert_eq!(token.access_token(), "impersonated_token");
+        assert_eq!(client.attempts(), 3);
+    }
+
+    #[test]
+    fn impersonated_email() {
+        let credentials = credentials(serde_json::json!({}), true);
+        assert_eq!(
+            credentials.impersonated_email(),
+            Some("sa@project.iam.gserviceaccount.com")
+        );
+    }
+
+    #[test]
+    fn missing_source() {
+        let credentials = credentials(serde_json::json!({}), false);
+        assert!(credentials.subject_token().is_err());
+    }
+}
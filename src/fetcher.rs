@@ -2,12 +2,29 @@
 //! at some configured time prior to the token's expiration.
 
 use crate::auth::{JwtClaims, Token};
-use crate::credentials::Credentials;
-use crate::{get_token_with_client_and_body, Result};
+use crate::credentials::{
+    AuthorizedUserCredentials, Credentials, ExternalAccountAuthorizedUserCredentials,
+};
+use crate::external_account::ExternalAccountCredentials;
+#[cfg(feature = "openssl")]
+use crate::get_token_with_client_and_body;
+use crate::http::{self, AttoHttpClient, HttpClient, TimeoutClient, TimeoutErr, Timeouts};
+use crate::impersonate::ImpersonatedCredentials;
+use crate::metadata::MetadataServer;
+use crate::retry::{jitter, RetryPolicy, RetryingClient};
+use crate::self_signed::SelfSignedJwt;
+use crate::signer::{self, Signer};
+use crate::source::TokenSource;
+use crate::{form_body, post_token_request, GoErr, Result};
 
 use arc_swap::ArcSwapOption;
+#[cfg(feature = "openssl")]
 use smpl_jwt::Jwt;
-use std::sync::{Arc, Mutex};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration as StdDuration, Instant};
 use time::{Duration, OffsetDateTime};
 
 /// A `TokenFetcher` stores a `Token` on first fetch and will continue returning
@@ -19,10 +36,67 @@ use time::{Duration, OffsetDateTime};
 /// is within the `refresh_buffer` window, it will fetch a new token, store
 /// that (along with the new expired time), and return the new token.
 pub struct TokenFetcher {
-    jwt: Arc<Mutex<Jwt<JwtClaims>>>,
-    credentials: Credentials,
+    source: Arc<dyn TokenSource>,
+    http_client: Arc<dyn HttpClient>,
     token_state: ArcSwapOption<TokenState>,
+    /// Held while refreshing, so concurrent callers don't all hit the token endpoint
+    refresh_lock: RefreshLock,
     refresh_buffer: Duration,
+    /// Set once a token that doesn't outlive `refresh_buffer` has been warned about
+    short_lived_warned: AtomicBool,
+    /// Stops the background refresher, if one was spawned, when the fetcher is dropped
+    refresher: Mutex<Option<Arc<StopSignal>>>,
+    /// Whether a stored token that is due for a refresh but not yet expired is
+    /// returned when the refresh fails
+    serve_stale: bool,
+    /// The last failed refresh, which callers served a stale token back off from
+    refresh_failure: Mutex<Option<RefreshFailure>>,
+    on_refresh_error: Option<RefreshErrorCallback>,
+    retry_policy: Option<RetryPolicy>,
+    timeouts: Timeouts,
+}
+
+/// JWTs made by a `Signer`, exchanged at `token_uri`
+struct SignerSource {
+    claims: Mutex<JwtClaims>,
+    signer: Arc<dyn Signer>,
+    token_uri: String,
+}
+
+impl TokenSource for SignerSource {
+    fn get_token(&self) -> Result<Token> {
+        self.get_token_with_http_client(&AttoHttpClient)
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        let claims = {
+            let mut claims = self.claims.lock().unwrap_or_else(PoisonError::into_inner);
+            claims.update(Some(OffsetDateTime::now_utc().unix_timestamp()), None);
+            claims.clone()
+        };
+        let jwt_body = signer::encode(&claims, self.signer.as_ref())?;
+        post_token_request(client, &self.token_uri, &form_body(&jwt_body))
+    }
+}
+
+/// A signed JWT exchanged at the token endpoint of the service account
+#[cfg(feature = "openssl")]
+struct JwtSource {
+    jwt: Arc<Mutex<Jwt<JwtClaims>>>,
+    credentials: Credentials,
+}
+
+#[cfg(feature = "openssl")]
+impl TokenSource for JwtSource {
+    fn get_token(&self) -> Result<Token> {
+        self.get_token_with_http_client(&AttoHttpClient)
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        let jwt_body =
+            TokenFetcher::get_jwt_body(&self.jwt, &self.credentials, OffsetDateTime::now_utc())?;
+        get_token_with_client_and_body(jwt_body, &self.credentials, client)
+    }
 }
 
 struct TokenState {
@@ -30,9 +104,28 @@ struct TokenState {
     token: Token,
     /// The lower bound of the time at which the token needs to be refreshed
     refresh_at: OffsetDateTime,
+    /// The time at which the token stops being accepted
+    expires_at: OffsetDateTime,
+}
+
+/// When a refresh last failed, and how long to wait before the next attempt
+#[derive(Clone, Copy)]
+struct RefreshFailure {
+    at: Instant,
+    backoff: StdDuration,
 }
 
+/// Called with the error of every failed refresh
+type RefreshErrorCallback = Box<dyn Fn(&GoErr) + Send + Sync>;
+
 impl TokenFetcher {
+    /// Deprecated like `with_client`, with the buffer in seconds
+    #[cfg(feature = "openssl")]
+    #[deprecated(
+        since = "0.17.0",
+        note = "the key of the `Jwt` is ignored, use `TokenFetcher::with_service_account`"
+    )]
+    #[allow(deprecated)]
     pub fn new(
         jwt: Jwt<JwtClaims>,
         credentials: Credentials,
@@ -41,90 +134,712 @@ impl TokenFetcher {
         TokenFetcher::with_client(jwt, credentials, Duration::new(refresh_buffer_seconds, 0))
     }
 
+    /// Fetches tokens for the claims of `jwt`. Deprecated: the key and header
+    /// of `jwt` are ignored, the claims are signed with the private key of
+    /// `credentials`, with its `private_key_id` as the `kid`. Use
+    /// `with_service_account`, which takes the claims alone.
+    #[cfg(feature = "openssl")]
+    #[deprecated(
+        since = "0.17.0",
+        note = "the key of the `Jwt` is ignored, use `TokenFetcher::with_service_account`"
+    )]
     pub fn with_client(
         jwt: Jwt<JwtClaims>,
         credentials: Credentials,
         refresh_buffer: Duration,
     ) -> TokenFetcher {
+        TokenFetcher::with_source(
+            Arc::new(JwtSource {
+                jwt: Arc::new(Mutex::new(jwt)),
+                credentials,
+            }),
+            refresh_buffer,
+        )
+    }
+
+    /// Fetches tokens for `claims`, signed with the private key of
+    /// `credentials`, with its `private_key_id` as the `kid`. Available
+    /// without the `openssl` feature.
+    pub fn with_service_account(
+        credentials: Credentials,
+        claims: JwtClaims,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        let token_uri = credentials.token_uri();
+        TokenFetcher::with_signer_source(claims, Arc::new(credentials), token_uri, refresh_buffer)
+    }
+
+    /// Fetches tokens for `claims`, signed by `signer`, e.g. an `IamSigner` or
+    /// a key held in Cloud KMS or an HSM. The JWTs are exchanged at the
+    /// `aud` of the claims, which must be the token endpoint.
+    pub fn with_signer(
+        claims: JwtClaims,
+        signer: Arc<dyn Signer>,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        let token_uri = claims.audience().to_string();
+        TokenFetcher::with_signer_source(claims, signer, token_uri, refresh_buffer)
+    }
+
+    fn with_signer_source(
+        claims: JwtClaims,
+        signer: Arc<dyn Signer>,
+        token_uri: String,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        TokenFetcher::with_source(
+            Arc::new(SignerSource {
+                claims: Mutex::new(claims),
+                signer,
+                token_uri,
+            }),
+            refresh_buffer,
+        )
+    }
+
+    /// Fetches tokens from the metadata server, for workloads on GCE, GKE
+    /// and Cloud Run that have no key file
+    pub fn with_metadata_server(
+        metadata: MetadataServer,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        TokenFetcher::with_source(Arc::new(metadata), refresh_buffer)
+    }
+
+    /// Fetches tokens by exchanging the refresh token of an end user, for
+    /// running locally with `gcloud auth application-default login`
+    pub fn with_authorized_user(
+        credentials: AuthorizedUserCredentials,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        TokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Fetches tokens by exchanging the refresh token of a workforce identity
+    /// federation user at the Security Token Service
+    pub fn with_external_account_authorized_user(
+        credentials: ExternalAccountAuthorizedUserCredentials,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        TokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Fetches tokens through Workload Identity Federation, exchanging a
+    /// subject token from a third party identity provider
+    pub fn with_external_account(
+        credentials: ExternalAccountCredentials,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        TokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Fetches tokens of a service account impersonated by a base identity,
+    /// the base identity's tokens are cached by its own `TokenFetcher`
+    pub fn with_impersonation(
+        credentials: ImpersonatedCredentials,
+        refresh_buffer: Duration,
+    ) -> TokenFetcher {
+        TokenFetcher::with_source(Arc::new(credentials), refresh_buffer)
+    }
+
+    /// Signs JWTs locally that are used as bearer tokens directly, without a
+    /// round trip to the token endpoint
+    pub fn with_self_signed_jwt(jwt: SelfSignedJwt, refresh_buffer: Duration) -> TokenFetcher {
+        TokenFetcher::with_source(Arc::new(jwt), refresh_buffer)
+    }
+
+    /// Caches the tokens of any `TokenSource`, e.g. one of your own or a fake
+    /// in tests. The http client, retry policy and timeouts of the fetcher
+    /// are passed to the source through `get_token_with_http_client`.
+    pub fn with_source(source: Arc<dyn TokenSource>, refresh_buffer: Duration) -> TokenFetcher {
         let token_state = ArcSwapOption::from(None);
 
         TokenFetcher {
-            jwt: Arc::new(Mutex::new(jwt)),
-            credentials,
+            source,
+            http_client: http::default_client(),
             token_state,
+            refresh_lock: RefreshLock::default(),
             refresh_buffer,
+            short_lived_warned: AtomicBool::new(false),
+            refresher: Mutex::new(None),
+            serve_stale: false,
+            refresh_failure: Mutex::new(None),
+            on_refresh_error: None,
+            retry_policy: None,
+            timeouts: Timeouts::default(),
         }
     }
 
+    /// Sends every request of the token source through `client` instead of the
+    /// default `attohttpc` transport. A base identity the source gets its tokens
+    /// from through a `TokenFetcher` of its own, as impersonated credentials do,
+    /// keeps that fetcher's client.
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> TokenFetcher {
+        self.http_client = client;
+        self
+    }
+
+    /// Retries requests of the token source that fail for transient reasons
+    /// according to `policy`. Like `with_http_client`, this doesn't reach a
+    /// base identity's own `TokenFetcher`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> TokenFetcher {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Bounds each request of the token source by `timeouts`. With a retry
+    /// policy the limits apply to each attempt; use the policy's deadline to
+    /// bound all of them. Like `with_http_client`, this doesn't reach a base
+    /// identity's own `TokenFetcher`, give it timeouts of its own.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> TokenFetcher {
+        self.timeouts = timeouts;
+        self
+    }
+
     /// Returns a token if the token is still considered "valid" per the
     /// currently stored token's `expires_in` field and the configured
     /// `refresh_buffer`. If it is, return the stored token. If not,
     /// fetch a new token, store it, and return the new token.
     pub fn fetch_token(&self) -> Result<Token> {
-        let token_state = self.token_state.load();
+        self.fetch(None)
+    }
 
-        match &*token_state {
-            // First time calling `fetch_token` since initialization, so fetch
-            // a token.
-            None => self.get_token(),
-            Some(token_state) => {
-                let now = OffsetDateTime::now_utc();
-
-                if now >= token_state.refresh_at {
-                    // We have an existing token but it is time to refresh it
-                    self.get_token()
-                } else {
-                    // We have an existing, valid token, so return immediately
-                    Ok(token_state.token.clone())
-                }
+    /// Like `fetch_token`, giving up by `deadline` with an error for which
+    /// `is_timeout` is true. The deadline bounds waiting for a refresh already
+    /// in flight, as well as every request and retry of a refresh, including
+    /// fetching the base identity token of impersonated credentials.
+    ///
+    /// ### Example
+    ///
+    /// ```rust no_run
+    /// use goauth::fetcher::TokenFetcher;
+    /// use goauth::metadata::MetadataServer;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let fetcher = TokenFetcher::with_metadata_server(MetadataServer::new(), time::Duration::new(60, 0));
+    /// match fetcher.fetch_token_with_deadline(Instant::now() + Duration::from_secs(2)) {
+    ///     Ok(token) => println!("{}", token),
+    ///     Err(e) if e.is_timeout() => eprintln!("no token in time: {}", e),
+    ///     Err(e) => panic!("{}", e),
+    /// }
+    /// ```
+    pub fn fetch_token_with_deadline(&self, deadline: Instant) -> Result<Token> {
+        self.fetch(Some(deadline))
+    }
+
+    fn fetch(&self, deadline: Option<Instant>) -> Result<Token> {
+        if let Some(token) = self.valid_token() {
+            return Ok(token);
+        }
+        if self.serve_stale {
+            if let Some(stale) = self.unexpired_token() {
+                return self.refresh_or_serve(stale, deadline);
             }
         }
+
+        // Refreshes are single-flight: one caller fetches a new token while
+        // the others wait for it, and are then served the token it stored
+        let _refreshing = self.refresh_lock.lock(deadline)?;
+        match self.valid_token() {
+            Some(token) => Ok(token),
+            None => self.try_refresh(deadline),
+        }
+    }
+
+    /// Refreshes the token if no other caller is doing so and the last failed
+    /// refresh has been backed off from, serving `stale` otherwise, or if the
+    /// refresh fails
+    fn refresh_or_serve(&self, stale: Token, deadline: Option<Instant>) -> Result<Token> {
+        let failure = *self
+            .refresh_failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(failure) = failure {
+            if failure.at.elapsed() < failure.backoff {
+                return Ok(stale);
+            }
+        }
+
+        let _refreshing = match self.refresh_lock.try_lock() {
+            Some(guard) => guard,
+            None => return Ok(stale),
+        };
+        if let Some(token) = self.valid_token() {
+            return Ok(token);
+        }
+        match self.try_refresh(deadline) {
+            Ok(token) => Ok(token),
+            Err(e) => {
+                log::warn!("token refresh failed, serving the stored token: {}", e);
+                Ok(stale)
+            }
+        }
+    }
+
+    /// Keep serving the stored token while it is unexpired if refreshing it
+    /// fails, rather than returning the error. Refreshes happen within
+    /// `refresh_buffer` of expiry, so this rides out token endpoint outages
+    /// of up to that long. Pair with `on_refresh_error` to be told of them.
+    ///
+    /// Callers never wait on a refresh while there is a token to serve: one of
+    /// them refreshes it, the others are served the stored token meanwhile.
+    /// After a failed refresh the next one is attempted only after a backoff,
+    /// growing from 1 to 30 seconds while refreshes keep failing.
+    pub fn serve_stale_on_error(mut self) -> TokenFetcher {
+        self.serve_stale = true;
+        self
+    }
+
+    /// Calls `callback` with the error of every failed refresh, including
+    /// those of the background refresher and those hidden by
+    /// `serve_stale_on_error`
+    pub fn on_refresh_error<F>(mut self, callback: F) -> TokenFetcher
+    where
+        F: Fn(&GoErr) + Send + Sync + 'static,
+    {
+        self.on_refresh_error = Some(Box::new(callback));
+        self
+    }
+
+    /// The stored token if it has not expired yet, even if it is due for a refresh
+    fn unexpired_token(&self) -> Option<Token> {
+        let token_state = self.token_state.load();
+        let token_state = token_state.as_ref()?;
+
+        if OffsetDateTime::now_utc() >= token_state.expires_at {
+            None
+        } else {
+            Some(token_state.token.clone())
+        }
+    }
+
+    /// Refreshes the token, reporting failures to the `on_refresh_error` callback
+    /// and recording them for callers to back off from
+    fn try_refresh(&self, deadline: Option<Instant>) -> Result<Token> {
+        let result = self.request_token(deadline);
+
+        let mut failure = self
+            .refresh_failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *failure = match (&result, *failure) {
+            (Ok(_), _) => None,
+            (Err(_), None) => Some(RefreshFailure {
+                at: Instant::now(),
+                backoff: MIN_RETRY_DELAY,
+            }),
+            (Err(_), Some(previous)) => Some(RefreshFailure {
+                at: Instant::now(),
+                backoff: (previous.backoff * 2).min(MAX_RETRY_DELAY),
+            }),
+        };
+        drop(failure);
+
+        if let (Err(e), Some(callback)) = (&result, &self.on_refresh_error) {
+            callback(e);
+        }
+        result
+    }
+
+    /// The stored token, unless there is none yet or it is time to refresh it
+    fn valid_token(&self) -> Option<Token> {
+        let token_state = self.token_state.load();
+        let token_state = token_state.as_ref()?;
+
+        if OffsetDateTime::now_utc() >= token_state.refresh_at {
+            None
+        } else {
+            Some(token_state.token.clone())
+        }
+    }
+
+    /// Refreshes the token even if the stored one is still valid, joining a
+    /// refresh already in flight
+    fn refresh(&self) -> Result<Token> {
+        let _refreshing = self.refresh_lock.lock(None)?;
+
+        self.try_refresh(None)
+    }
+
+    /// The time at which the stored token needs to be refreshed, if there is one
+    fn refresh_at(&self) -> Option<OffsetDateTime> {
+        self.token_state
+            .load()
+            .as_ref()
+            .map(|token_state| token_state.refresh_at)
+    }
+
+    /// Spawns a thread that refreshes the token shortly before `refresh_at`, so
+    /// that `fetch_token` callers never wait on the token endpoint. Failed
+    /// refreshes are retried with backoff while the stored token keeps being
+    /// served.
+    ///
+    /// The thread stops when the fetcher is dropped or `RefreshHandle::shutdown`
+    /// is called. Spawning a second refresher stops the first one.
+    ///
+    /// ### Example
+    ///
+    /// ```rust no_run
+    /// use goauth::fetcher::TokenFetcher;
+    /// use goauth::metadata::MetadataServer;
+    /// use std::sync::Arc;
+    /// use time::Duration;
+    ///
+    /// let fetcher = Arc::new(TokenFetcher::with_metadata_server(MetadataServer::new(), Duration::new(60, 0)));
+    /// let refresher = fetcher.spawn_background_refresh().unwrap();
+    /// let token = fetcher.fetch_token().unwrap();
+    /// refresher.shutdown();
+    /// ```
+    pub fn spawn_background_refresh(self: &Arc<Self>) -> Result<RefreshHandle> {
+        let signal = Arc::new(StopSignal::default());
+        let fetcher = Arc::downgrade(self);
+        let thread_signal = signal.clone();
+        let thread = thread::Builder::new()
+            .name("goauth-refresh".to_string())
+            .spawn(move || run_refresher(fetcher, &thread_signal))?;
+
+        let previous = self
+            .refresher
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(signal.clone());
+        if let Some(previous) = previous {
+            previous.stop();
+        }
+
+        Ok(RefreshHandle {
+            signal,
+            thread: Some(thread),
+        })
     }
 
     /// Refresh the token
-    fn get_token(&self) -> Result<Token> {
+    fn request_token(&self, deadline: Option<Instant>) -> Result<Token> {
         let now = OffsetDateTime::now_utc();
-        let jwt_body = self.get_jwt_body(now)?;
-        let token = get_token_with_client_and_body(jwt_body, &self.credentials)?;
+        let mut http_client = self.http_client.clone();
+        if !self.timeouts.is_unset() || deadline.is_some() {
+            let mut client = TimeoutClient::new(http_client, self.timeouts);
+            if let Some(deadline) = deadline {
+                client = client.with_deadline(deadline);
+            }
+            http_client = Arc::new(client);
+        }
+        if let Some(policy) = &self.retry_policy {
+            let policy = match deadline {
+                Some(deadline) => policy.clone().until(deadline),
+                None => policy.clone(),
+            };
+            http_client = Arc::new(RetryingClient::new(http_client, policy));
+        }
+        let token = self
+            .source
+            .get_token_with_http_client(http_client.as_ref())?;
         let expires_in = Duration::new(token.expires_in().into(), 0);
-
-        assert!(
-            expires_in >= self.refresh_buffer,
-            "Received a token whose expires_in is less than the configured refresh buffer!"
-        );
-
-        let refresh_at = now + (expires_in - self.refresh_buffer);
+        let refresh_at =
+            now + refresh_after(expires_in, self.refresh_buffer, &self.short_lived_warned);
         let token_state = TokenState {
             token: token.clone(),
             refresh_at,
+            expires_at: now + expires_in,
         };
 
         self.token_state.swap(Some(Arc::new(token_state)));
         Ok(token)
     }
 
-    #[allow(clippy::result_large_err)]
-    fn get_jwt_body(&self, valid_from: OffsetDateTime) -> Result<String> {
-        let mut jwt = self.jwt.lock().unwrap();
-        // Refresh jwt claims
-        jwt.body_mut()
-            .update(Some(valid_from.unix_timestamp()), None);
-        let jwt_body = jwt.finalize()?;
-        Ok(jwt_body)
+    /// Refreshes the claims of `jwt` and signs them with the key of
+    /// `credentials`, so the header carries its `private_key_id`
+    #[cfg(feature = "openssl")]
+    fn get_jwt_body(
+        jwt: &Mutex<Jwt<JwtClaims>>,
+        credentials: &Credentials,
+        valid_from: OffsetDateTime,
+    ) -> Result<String> {
+        let claims = {
+            // The claims are rewritten in full below, so a panic while the lock
+            // was held can't have left anything behind that matters
+            let mut jwt = jwt.lock().unwrap_or_else(PoisonError::into_inner);
+            // Refresh jwt claims
+            jwt.body_mut()
+                .update(Some(valid_from.unix_timestamp()), None);
+            jwt.body().clone()
+        };
+        signer::encode(&claims, credentials)
+    }
+}
+
+/// The soonest a token is refreshed after being fetched, so tokens that expire
+/// right away don't have every fetch, or a background refresher, hit the token
+/// endpoint back to back
+pub(crate) const MIN_REFRESH_AFTER: Duration = Duration::seconds(1);
+
+/// How long after being fetched a token is refreshed: `refresh_buffer` before
+/// it expires, or halfway through its lifetime if it doesn't live longer than
+/// the buffer, and no sooner than `MIN_REFRESH_AFTER`. The latter is warned
+/// about the first time only, `warned` records that it was.
+pub(crate) fn refresh_after(
+    expires_in: Duration,
+    refresh_buffer: Duration,
+    warned: &AtomicBool,
+) -> Duration {
+    let refresh_after = if expires_in > refresh_buffer {
+        expires_in - refresh_buffer
+    } else {
+        if !warned.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "token expires in {}, within the refresh buffer of {}, refreshing it halfway through",
+                expires_in,
+                refresh_buffer
+            );
+        }
+        expires_in / 2
+    };
+    refresh_after.max(MIN_REFRESH_AFTER)
+}
+
+impl Drop for TokenFetcher {
+    fn drop(&mut self) {
+        let refresher = self
+            .refresher
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(signal) = refresher.take() {
+            signal.stop();
+        }
+    }
+}
+
+/// Serves the cached token, the fetcher's own http client, retry policy and
+/// timeouts are used to refresh it. Only the deadline of the client passed to
+/// `get_token_with_http_client` is taken from it.
+impl TokenSource for TokenFetcher {
+    fn get_token(&self) -> Result<Token> {
+        self.fetch_token()
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        match client.deadline() {
+            Some(deadline) => self.fetch_token_with_deadline(deadline),
+            None => self.fetch_token(),
+        }
+    }
+}
+
+/// The most a background refresh is moved ahead of `refresh_at`, so fetchers
+/// created together don't all refresh at the same instant
+pub(crate) const REFRESH_JITTER: StdDuration = StdDuration::from_secs(10);
+/// Backoff bounds between retries of a failed refresh
+pub(crate) const MIN_RETRY_DELAY: StdDuration = StdDuration::from_secs(1);
+pub(crate) const MAX_RETRY_DELAY: StdDuration = StdDuration::from_secs(30);
+
+/// Held while refreshing, released by dropping the `RefreshGuard`. Unlike a
+/// `Mutex`, waiting for it can be given up on at a deadline.
+#[derive(Default)]
+pub(crate) struct RefreshLock {
+    refreshing: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl RefreshLock {
+    /// Takes the lock, waiting for it no later than `deadline`
+    pub(crate) fn lock(&self, deadline: Option<Instant>) -> Result<RefreshGuard<'_>> {
+        let mut refreshing = self
+            .refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        while *refreshing {
+            refreshing = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining == StdDuration::from_secs(0) {
+                        return Err(GoErr::from(TimeoutErr::new("waiting for a token refresh")));
+                    }
+                    self.condvar
+                        .wait_timeout(refreshing, remaining)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .condvar
+                    .wait(refreshing)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        *refreshing = true;
+        Ok(RefreshGuard(self))
+    }
+
+    /// Takes the lock if no one holds it
+    fn try_lock(&self) -> Option<RefreshGuard<'_>> {
+        let mut refreshing = self
+            .refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if *refreshing {
+            return None;
+        }
+        *refreshing = true;
+        Some(RefreshGuard(self))
+    }
+}
+
+/// Releases the `RefreshLock` when dropped, waking up the callers waiting for it
+pub(crate) struct RefreshGuard<'a>(&'a RefreshLock);
+
+impl Drop for RefreshGuard<'_> {
+    fn drop(&mut self) {
+        *self
+            .0
+            .refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = false;
+        self.0.condvar.notify_all();
+    }
+}
+
+/// Tells a background refresher to stop, waking it up if it is waiting
+#[derive(Default)]
+struct StopSignal {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl StopSignal {
+    fn stop(&self) {
+        *self.stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.condvar.notify_all();
+    }
+
+    /// Waits for `timeout` or until stopped, returns whether we were stopped
+    fn wait(&self, timeout: StdDuration) -> bool {
+        let stopped = self.stopped.lock().unwrap_or_else(PoisonError::into_inner);
+        let (stopped, _) = self
+            .condvar
+            .wait_timeout_while(stopped, timeout, |stopped| !*stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        *stopped
+    }
+}
+
+/// Controls the thread spawned by `TokenFetcher::spawn_background_refresh`.
+/// Dropping the handle leaves the thread running until the fetcher is dropped.
+pub struct RefreshHandle {
+    signal: Arc<StopSignal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RefreshHandle {
+    /// Whether the refresher thread is still running
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .map(|thread| !thread.is_finished())
+            .unwrap_or(false)
+    }
+
+    /// Stops the refresher and waits for its thread to finish. A refresh in
+    /// flight is completed first.
+    pub fn shutdown(mut self) {
+        self.signal.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// How long a background refresher waits before its next refresh. `backoff`
+/// is set while refreshes fail, or hand out tokens that are due for a refresh
+/// as soon as they are stored.
+pub(crate) fn refresher_delay(
+    backoff: Option<StdDuration>,
+    refresh_at: Option<OffsetDateTime>,
+) -> StdDuration {
+    match (backoff, refresh_at) {
+        (Some(backoff), _) => backoff + jitter(backoff / 2),
+        // Nothing fetched yet, so fetch right away
+        (None, None) => StdDuration::ZERO,
+        (None, Some(refresh_at)) => {
+            let until = refresh_at - OffsetDateTime::now_utc();
+            let until = StdDuration::try_from(until).unwrap_or(StdDuration::ZERO);
+            let jitter = jitter(REFRESH_JITTER.min(until / 10));
+            until.saturating_sub(jitter)
+        }
+    }
+}
+
+/// The backoff of a background refresher after a refresh with `result`,
+/// which stored a token due at `refresh_at`
+pub(crate) fn refresher_backoff<T>(
+    backoff: Option<StdDuration>,
+    result: &Result<T>,
+    refresh_at: Option<OffsetDateTime>,
+) -> Option<StdDuration> {
+    let next_backoff = backoff.map_or(MIN_RETRY_DELAY, |backoff| {
+        (backoff * 2).min(MAX_RETRY_DELAY)
+    });
+    match result {
+        Ok(_) if refresh_at > Some(OffsetDateTime::now_utc()) => None,
+        Ok(_) => {
+            log::warn!("refreshed token is due for a refresh already, backing off");
+            Some(next_backoff)
+        }
+        Err(e) => {
+            log::warn!("background token refresh failed, retrying: {}", e);
+            Some(next_backoff)
+        }
+    }
+}
+
+fn run_refresher(fetcher: Weak<TokenFetcher>, signal: &StopSignal) {
+    let mut backoff = None;
+
+    loop {
+        let refresh_at = match fetcher.upgrade() {
+            Some(fetcher) => fetcher.refresh_at(),
+            // The fetcher is gone
+            None => return,
+        };
+        if signal.wait(refresher_delay(backoff, refresh_at)) {
+            return;
+        }
+
+        let (result, refresh_at) = match fetcher.upgrade() {
+            Some(fetcher) => (fetcher.refresh(), fetcher.refresh_at()),
+            None => return,
+        };
+        backoff = refresher_backoff(backoff, &result, refresh_at);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    // Most of these tests build their fetcher out of a `Jwt`, which needs the
+    // `openssl` feature. The deprecated `Jwt` constructors are still covered.
+    #![cfg_attr(not(feature = "openssl"), allow(unused_imports))]
+    #![allow(deprecated)]
+
     use crate::auth::{JwtClaims, Token};
-    use crate::credentials::Credentials;
+    use crate::credentials::{AuthorizedUserCredentials, Credentials};
+    #[cfg(feature = "openssl")]
+    use crate::fetcher::JwtSource;
     use crate::fetcher::TokenFetcher;
+    use crate::http::{HttpClient, HttpRequest, HttpResponse, Timeouts};
+    use crate::metadata::MetadataServer;
+    use crate::retry::tests::ScriptedClient;
+    use crate::retry::RetryPolicy;
     use crate::scopes::Scope;
-    use mockito::{self, mock};
+    use crate::self_signed::SelfSignedJwt;
+    use mockito::{self, mock, Matcher};
+    #[cfg(feature = "openssl")]
     use smpl_jwt::Jwt;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier, Condvar, Mutex};
     use std::thread;
-    use std::time::Duration as StdDuration;
+    use std::time::{Duration as StdDuration, Instant};
+    use time::Duration;
 
+    #[cfg(feature = "openssl")]
     fn get_mocks() -> (Jwt<JwtClaims>, Credentials) {
         let token_url = mockito::server_url();
         let iss = "some_iss";
@@ -136,7 +851,7 @@ mod tests {
         let claims = JwtClaims::new(
             String::from(iss),
             &[Scope::DevStorageReadWrite],
-            String::from(token_url.clone()),
+            token_url.clone(),
             None,
             None,
         );
@@ -158,6 +873,7 @@ mod tests {
         (token, json.to_string())
     }
 
+    #[cfg(feature = "openssl")]
     #[test]
     fn basic_token_fetch() {
         let (jwt, credentials) = get_mocks();
@@ -173,6 +889,26 @@ mod tests {
         assert_eq!(expected_token, token);
     }
 
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn jwt_carries_private_key_id() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/kid", mockito::server_url());
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(0, 0));
+
+        // The encoded `{"alg":"RS256","typ":"JWT","kid":"dummy"}`
+        let _mock = mock("POST", "/kid")
+            .match_body(Matcher::Regex(
+                "assertion=eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6ImR1bW15In0".to_string(),
+            ))
+            .with_status(200)
+            .with_body(token_json("kid_token", "Bearer", 3600).1)
+            .create();
+
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "kid_token");
+    }
+
+    #[cfg(feature = "openssl")]
     #[test]
     fn basic_token_refresh() {
         let (jwt, credentials) = get_mocks();
@@ -201,6 +937,7 @@ mod tests {
         mock.assert();
     }
 
+    #[cfg(feature = "openssl")]
     #[test]
     fn token_refresh_with_buffer() {
         let (jwt, credentials) = get_mocks();
@@ -230,6 +967,7 @@ mod tests {
         mock.assert();
     }
 
+    #[cfg(feature = "openssl")]
     #[test]
     fn doesnt_token_refresh_unnecessarily() {
         let (jwt, credentials) = get_mocks();
@@ -255,7 +993,537 @@ mod tests {
         mock.assert();
     }
 
+    #[test]
+    fn metadata_token_is_cached() {
+        let metadata = MetadataServer::with_host(&mockito::server_address().to_string());
+        let fetcher = TokenFetcher::with_metadata_server(metadata, Duration::new(0, 0));
+
+        let (expected_token, json) = token_json("token", "Bearer", 60);
+
+        let mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/token",
+        )
+        .match_header("Metadata-Flavor", "Google")
+        .with_status(200)
+        .with_body(json)
+        .expect(1)
+        .create();
+
+        assert_eq!(expected_token, fetcher.fetch_token().unwrap());
+        assert_eq!(expected_token, fetcher.fetch_token().unwrap());
+
+        mock.assert();
+    }
+
+    #[test]
+    fn authorized_user_token_refresh() {
+        let mut credentials = AuthorizedUserCredentials::from_str(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        )
+        .unwrap();
+        credentials.token_uri = mockito::server_url();
+        let fetcher = TokenFetcher::with_authorized_user(credentials, Duration::new(0, 0));
+
+        let expires_in = 1;
+        let (expected_token, json) = token_json("token", "Bearer", expires_in);
+
+        let mock = mock("POST", "/")
+            .match_body(Matcher::UrlEncoded(
+                "grant_type".to_string(),
+                "refresh_token".to_string(),
+            ))
+            .with_status(200)
+            .with_body(json)
+            .expect(2) // we expect to be hit twice due to refresh
+            .create();
+
+        assert_eq!(expected_token, fetcher.fetch_token().unwrap());
+
+        thread::sleep(StdDuration::from_secs(expires_in.into()));
+
+        assert_eq!(expected_token, fetcher.fetch_token().unwrap());
+
+        mock.assert();
+    }
+
+    #[test]
+    fn self_signed_jwt_is_cached() {
+        let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        let jwt = SelfSignedJwt::with_audience(credentials, "https://pubsub.googleapis.com/");
+        let fetcher = TokenFetcher::with_self_signed_jwt(jwt, Duration::new(60, 0));
+
+        let first = fetcher.fetch_token().unwrap();
+        thread::sleep(StdDuration::from_millis(1100));
+        let second = fetcher.fetch_token().unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn custom_http_client() {
+        struct FakeClient(AtomicUsize);
+
+        impl HttpClient for FakeClient {
+            fn send(&self, request: HttpRequest) -> crate::Result<HttpResponse> {
+                assert_eq!(request.url(), "https://fake.example.com/token");
+                self.0.fetch_add(1, Ordering::SeqCst);
+                let (_, json) = token_json("fake_token", "Bearer", 3600);
+                Ok(HttpResponse::new(200, json.into_bytes()))
+            }
+        }
+
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = "https://fake.example.com/token".to_string();
+        let client = Arc::new(FakeClient(AtomicUsize::new(0)));
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0))
+            .with_http_client(client.clone());
+
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "fake_token");
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "fake_token");
+        assert_eq!(client.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn concurrent_refresh_is_single_flight() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/single_flight", mockito::server_url());
+        let fetcher = Arc::new(TokenFetcher::with_client(
+            jwt,
+            credentials,
+            Duration::new(0, 0),
+        ));
+
+        let (expected_token, json) = token_json("single_flight", "Bearer", 3600);
+        let mock = mock("POST", "/single_flight")
+            .with_status(200)
+            .with_body(json)
+            .expect(1)
+            .create();
+
+        let barrier = Arc::new(Barrier::new(16));
+        let handles = (0..16)
+            .map(|_| {
+                let fetcher = fetcher.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    fetcher.fetch_token().unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected_token);
+        }
+        mock.assert();
+    }
+
+    /// Hands out tokens `recorded_{n}`, the n-th one expiring in the n-th of
+    /// `expires_in` (or the last) seconds, and records when each was
+    /// requested so tests can wait for requests rather than sleep
+    struct RecordingClient {
+        expires_in: Vec<u32>,
+        sent: Mutex<Vec<Instant>>,
+        condvar: Condvar,
+    }
+
+    impl RecordingClient {
+        fn new(expires_in: &[u32]) -> RecordingClient {
+            RecordingClient {
+                expires_in: expires_in.to_vec(),
+                sent: Mutex::new(Vec::new()),
+                condvar: Condvar::new(),
+            }
+        }
+
+        /// Waits for `requests` requests, returning when each was sent
+        fn wait_for(&self, requests: usize) -> Vec<Instant> {
+            let sent = self.sent.lock().unwrap();
+            let (sent, wait) = self
+                .condvar
+                .wait_timeout_while(sent, StdDuration::from_secs(10), |sent| {
+                    sent.len() < requests
+                })
+                .unwrap();
+            assert!(!wait.timed_out(), "only {} requests were sent", sent.len());
+            sent.clone()
+        }
+
+        fn requests(&self) -> usize {
+            self.sent.lock().unwrap().len()
+        }
+    }
+
+    impl HttpClient for RecordingClient {
+        fn send(&self, _request: HttpRequest) -> crate::Result<HttpResponse> {
+            let mut sent = self.sent.lock().unwrap();
+            let n = sent.len();
+            sent.push(Instant::now());
+            self.condvar.notify_all();
+
+            let expires_in = self.expires_in[n.min(self.expires_in.len() - 1)];
+            let (_, json) = token_json(&format!("recorded_{}", n), "Bearer", expires_in);
+            Ok(HttpResponse::new(200, json.into_bytes()))
+        }
+    }
+
+    fn service_account_fetcher(
+        refresh_buffer: Duration,
+        client: Arc<dyn HttpClient>,
+    ) -> TokenFetcher {
+        let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        let claims = JwtClaims::new(
+            credentials.iss(),
+            &[Scope::DevStorageReadWrite],
+            credentials.token_uri(),
+            None,
+            None,
+        );
+        TokenFetcher::with_service_account(credentials, claims, refresh_buffer)
+            .with_http_client(client)
+    }
+
+    #[test]
+    fn background_refresh_ahead_of_callers() {
+        // The first token is due for a refresh a second after it is fetched
+        let client = Arc::new(RecordingClient::new(&[2, 3600]));
+        let fetcher = Arc::new(service_account_fetcher(Duration::new(1, 0), client.clone()));
+
+        let refresher = fetcher.spawn_background_refresh().unwrap();
+        client.wait_for(2);
+        // Completes the refresh in flight, so its token is stored
+        refresher.shutdown();
+
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "recorded_1");
+        assert_eq!(client.requests(), 2);
+    }
+
+    #[test]
+    fn background_refresh_of_tokens_that_expire_right_away() {
+        let client = Arc::new(RecordingClient::new(&[0]));
+        let fetcher = Arc::new(service_account_fetcher(
+            Duration::new(60, 0),
+            client.clone(),
+        ));
+
+        let refresher = fetcher.spawn_background_refresh().unwrap();
+        let sent = client.wait_for(3);
+        refresher.shutdown();
+
+        // Refreshes are a second apart, less the jitter of a tenth of the
+        // wait, rather than back to back
+        for pair in sent.windows(2) {
+            assert!(pair[1] - pair[0] >= StdDuration::from_millis(900));
+        }
+    }
+
+    #[test]
+    fn background_refresh_stops_with_fetcher() {
+        let client = Arc::new(RecordingClient::new(&[3600]));
+        let fetcher = Arc::new(service_account_fetcher(
+            Duration::new(60, 0),
+            client.clone(),
+        ));
+
+        let refresher = fetcher.spawn_background_refresh().unwrap();
+        client.wait_for(1);
+        assert!(refresher.is_running());
+
+        // Dropping the fetcher wakes the refresher up, which then finishes
+        drop(fetcher);
+        let deadline = Instant::now() + StdDuration::from_secs(10);
+        while refresher.is_running() && Instant::now() < deadline {
+            thread::yield_now();
+        }
+        assert!(!refresher.is_running());
+        assert_eq!(client.requests(), 1);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn stale_token_served_while_unexpired() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/stale", mockito::server_url());
+        let failures = Arc::new(AtomicUsize::new(0));
+        let counter = failures.clone();
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(1, 0))
+            .serve_stale_on_error()
+            .on_refresh_error(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+
+        let (expected_token, json) = token_json("stale", "Bearer", 2);
+        let ok = mock("POST", "/stale")
+            .with_status(200)
+            .with_body(json)
+            .create();
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+        drop(ok);
+
+        let _unavailable = mock("POST", "/stale")
+            .with_status(503)
+            .with_body("unavailable")
+            .create();
+
+        // Due for a refresh, which fails, but still unexpired
+        thread::sleep(StdDuration::from_millis(1100));
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+
+        // Expired
+        thread::sleep(StdDuration::from_millis(1000));
+        assert!(fetcher.fetch_token().is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn stale_token_served_without_waiting_on_a_hung_refresh() {
+        /// Hands out a token, then hangs until released before failing
+        #[derive(Default)]
+        struct HangingClient {
+            /// Requests sent, and whether the hung one has been released
+            state: Mutex<(usize, bool)>,
+            condvar: Condvar,
+        }
+
+        impl HangingClient {
+            fn requests(&self) -> usize {
+                self.state.lock().unwrap().0
+            }
+
+            fn wait_until_hung(&self) {
+                let state = self.state.lock().unwrap();
+                let (_state, wait) = self
+                    .condvar
+                    .wait_timeout_while(state, StdDuration::from_secs(10), |state| state.0 < 2)
+                    .unwrap();
+                assert!(!wait.timed_out());
+            }
+
+            fn release(&self) {
+                self.state.lock().unwrap().1 = true;
+                self.condvar.notify_all();
+            }
+        }
+
+        impl HttpClient for HangingClient {
+            fn send(&self, _request: HttpRequest) -> crate::Result<HttpResponse> {
+                let mut state = self.state.lock().unwrap();
+                state.0 += 1;
+                self.condvar.notify_all();
+                if state.0 == 1 {
+                    let (_, json) = token_json("hung", "Bearer", 30);
+                    return Ok(HttpResponse::new(200, json.into_bytes()));
+                }
+                let _released = self.condvar.wait_while(state, |state| !state.1).unwrap();
+                Ok(HttpResponse::new(503, Vec::new()))
+            }
+        }
+
+        let client = Arc::new(HangingClient::default());
+        let fetcher = Arc::new(
+            service_account_fetcher(Duration::new(29, 0), client.clone()).serve_stale_on_error(),
+        );
+        let stored = fetcher.fetch_token().unwrap();
+
+        // Due for a refresh a second after it was fetched, unexpired for 30
+        thread::sleep(StdDuration::from_millis(1100));
+        let hung = {
+            let fetcher = fetcher.clone();
+            thread::spawn(move || fetcher.fetch_token().unwrap())
+        };
+        client.wait_until_hung();
+
+        // One caller hangs on the refresh, the others are served the stored
+        // token without waiting for it to be released
+        for _ in 0..4 {
+            assert_eq!(fetcher.fetch_token().unwrap(), stored);
+        }
+        assert_eq!(client.requests(), 2);
+
+        client.release();
+        assert_eq!(hung.join().unwrap(), stored);
+
+        // The failed refresh is backed off from
+        assert_eq!(fetcher.fetch_token().unwrap(), stored);
+        assert_eq!(client.requests(), 2);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn refresh_error_returned_by_default() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/not_stale", mockito::server_url());
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(1, 0));
+
+        let (_, json) = token_json("not_stale", "Bearer", 2);
+        let ok = mock("POST", "/not_stale")
+            .with_status(200)
+            .with_body(json)
+            .create();
+        fetcher.fetch_token().unwrap();
+        drop(ok);
+
+        let _unavailable = mock("POST", "/not_stale")
+            .with_status(503)
+            .with_body("unavailable")
+            .create();
+
+        thread::sleep(StdDuration::from_millis(1100));
+        assert!(fetcher.fetch_token().is_err());
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn retries_transient_failures() {
+        let (_, json) = token_json("retried", "Bearer", 3600);
+        let client = Arc::new(ScriptedClient::new(&[(503, "unavailable"), (200, &json)]));
+
+        let (jwt, credentials) = get_mocks();
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0))
+            .with_http_client(client.clone())
+            .with_retry_policy(
+                RetryPolicy::new()
+                    .with_backoff(StdDuration::from_millis(1), StdDuration::from_millis(1)),
+            );
+
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "retried");
+        assert_eq!(client.attempts(), 2);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn timeouts_applied_to_requests() {
+        struct TimeoutsClient(Mutex<Option<Timeouts>>);
+
+        impl HttpClient for TimeoutsClient {
+            fn send(&self, request: HttpRequest) -> crate::Result<HttpResponse> {
+                *self.0.lock().unwrap() = Some(request.timeouts());
+                let (_, json) = token_json("bounded", "Bearer", 3600);
+                Ok(HttpResponse::new(200, json.into_bytes()))
+            }
+        }
+
+        let client = Arc::new(TimeoutsClient(Mutex::new(None)));
+        let (jwt, credentials) = get_mocks();
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0))
+            .with_http_client(client.clone())
+            .with_timeouts(
+                Timeouts::new()
+                    .with_read(StdDuration::from_secs(3))
+                    .with_total(StdDuration::from_secs(60)),
+            );
+
+        let deadline = Instant::now() + StdDuration::from_secs(5);
+        fetcher.fetch_token_with_deadline(deadline).unwrap();
+
+        let timeouts = client.0.lock().unwrap().unwrap();
+        assert_eq!(timeouts.read(), Some(StdDuration::from_secs(3)));
+        assert!(timeouts.total().unwrap() <= StdDuration::from_secs(5));
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn deadline_while_refresh_in_flight() {
+        let (jwt, credentials) = get_mocks();
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0));
+
+        // Another caller is refreshing and doesn't finish in time
+        let _refreshing = fetcher.refresh_lock.lock(None).unwrap();
+        let err = fetcher
+            .fetch_token_with_deadline(Instant::now() + StdDuration::from_millis(20))
+            .unwrap_err();
+
+        assert!(err.is_timeout());
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn expired_deadline_fails_without_a_request() {
+        let client = Arc::new(ScriptedClient::new(&[(200, "{}")]));
+        let (jwt, credentials) = get_mocks();
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0))
+            .with_http_client(client.clone());
+
+        let err = fetcher
+            .fetch_token_with_deadline(Instant::now())
+            .unwrap_err();
+
+        assert!(err.is_timeout());
+        assert_eq!(client.attempts(), 0);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn token_shorter_than_refresh_buffer() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/short_lived", mockito::server_url());
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0));
+
+        let (expected_token, json) = token_json("short_lived", "Bearer", 2);
+        let mock = mock("POST", "/short_lived")
+            .with_status(200)
+            .with_body(json)
+            .expect(2)
+            .create();
+
+        // Served from the cache for the first half of its lifetime
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+
+        // And refreshed after
+        thread::sleep(StdDuration::from_millis(1100));
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+        mock.assert();
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn expired_token_does_not_panic() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/expired", mockito::server_url());
+        let fetcher = TokenFetcher::with_client(jwt, credentials, Duration::new(60, 0));
+
+        let (expected_token, json) = token_json("expired", "Bearer", 0);
+        let _mock = mock("POST", "/expired")
+            .with_status(200)
+            .with_body(json)
+            .create();
+
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn recovers_from_poisoned_jwt_lock() {
+        let (jwt, mut credentials) = get_mocks();
+        credentials.token_uri = format!("{}/poisoned", mockito::server_url());
+        let jwt = Arc::new(Mutex::new(jwt));
+        let source = JwtSource {
+            jwt: jwt.clone(),
+            credentials,
+        };
+        let fetcher = TokenFetcher::with_source(Arc::new(source), Duration::new(60, 0));
+
+        let _ = thread::spawn(move || {
+            let _guard = jwt.lock().unwrap();
+            panic!("poisoning the jwt lock");
+        })
+        .join();
+
+        let (expected_token, json) = token_json("poisoned", "Bearer", 3600);
+        let _mock = mock("POST", "/poisoned")
+            .with_status(200)
+            .with_body(json)
+            .create();
+
+        assert_eq!(fetcher.fetch_token().unwrap(), expected_token);
+    }
+
     /// Ensure that `TokenFetcher` is `Send` and `Sync`
+    #[cfg(feature = "openssl")]
     #[test]
     fn is_send_and_sync() {
         let (jwt, credentials) = get_mocks();
@@ -266,4 +1534,36 @@ mod tests {
 
         check(&fetcher);
     }
+
+    #[test]
+    fn short_lived_tokens_are_refreshed_no_sooner_than_a_second() {
+        use crate::fetcher::{refresh_after, MIN_REFRESH_AFTER};
+        use std::sync::atomic::AtomicBool;
+
+        let warned = AtomicBool::new(false);
+        let buffer = Duration::new(60, 0);
+        assert_eq!(
+            refresh_after(Duration::new(3600, 0), buffer, &warned),
+            Duration::new(3540, 0)
+        );
+        assert!(!warned.load(Ordering::SeqCst));
+
+        assert_eq!(
+            refresh_after(Duration::new(30, 0), buffer, &warned),
+            Duration::new(15, 0)
+        );
+        assert!(warned.load(Ordering::SeqCst));
+        assert_eq!(
+            refresh_after(Duration::new(1, 0), buffer, &warned),
+            MIN_REFRESH_AFTER
+        );
+        assert_eq!(
+            refresh_after(Duration::ZERO, buffer, &warned),
+            MIN_REFRESH_AFTER
+        );
+        assert_eq!(
+            refresh_after(Duration::new(61, 0), buffer, &warned),
+            MIN_REFRESH_AFTER
+        );
+    }
 }
@@ -0,0 +1,685 @@
+//! The HTTP transport token requests are sent through.
+//!
+//! `HttpClient` is a minimal blocking client: a request goes in, a status and
+//! body come out. Implement it to reuse your own connection pool, proxy and
+//! TLS configuration, or to fake the token endpoint in tests. `AttoHttpClient`
+//! is the default; `reqwest::blocking::Client` and `ureq::Agent` implement it
+//! with the `reqwest-client` and `ureq-client` features.
+
+use crate::{GoErr, Result};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+        }
+    }
+}
+
+/// How long a request may take. `connect` bounds establishing the
+/// connection, `read` each read of the response and `total` the whole
+/// request. Unset limits are left to the transport, which for the default
+/// `attohttpc` transport means no limit.
+///
+/// ### Example
+///
+/// ```rust
+/// use goauth::http::Timeouts;
+/// use std::time::Duration;
+///
+/// let timeouts = Timeouts::new()
+///     .with_connect(Duration::from_secs(2))
+///     .with_read(Duration::from_secs(5))
+///     .with_total(Duration::from_secs(10));
+/// assert_eq!(timeouts.total(), Some(Duration::from_secs(10)));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    connect: Option<Duration>,
+    read: Option<Duration>,
+    total: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new() -> Timeouts {
+        Timeouts::default()
+    }
+
+    pub fn with_connect(mut self, timeout: Duration) -> Timeouts {
+        self.connect = Some(timeout);
+        self
+    }
+
+    pub fn with_read(mut self, timeout: Duration) -> Timeouts {
+        self.read = Some(timeout);
+        self
+    }
+
+    pub fn with_total(mut self, timeout: Duration) -> Timeouts {
+        self.total = Some(timeout);
+        self
+    }
+
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect
+    }
+
+    pub fn read(&self) -> Option<Duration> {
+        self.read
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        self.total
+    }
+
+    pub fn is_unset(&self) -> bool {
+        *self == Timeouts::default()
+    }
+
+    /// The tighter of each pair of limits
+    pub(crate) fn tightest(self, other: Timeouts) -> Timeouts {
+        fn min(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        Timeouts {
+            connect: min(self.connect, other.connect),
+            read: min(self.read, other.read),
+            total: min(self.total, other.total),
+        }
+    }
+
+    /// These limits, with `total` cut down to what is left until `deadline`
+    pub(crate) fn until(self, deadline: Instant) -> Timeouts {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        self.tightest(Timeouts::new().with_total(remaining))
+    }
+}
+
+/// A request or a wait for a token that ran out of time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutErr {
+    what: String,
+}
+
+impl TimeoutErr {
+    pub(crate) fn new(what: &str) -> TimeoutErr {
+        TimeoutErr { what: what.into() }
+    }
+
+    pub(crate) fn request(url: &str) -> TimeoutErr {
+        TimeoutErr::new(&format!("request to {}", url))
+    }
+}
+
+impl fmt::Display for TimeoutErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} timed out", self.what)
+    }
+}
+
+impl std::error::Error for TimeoutErr {}
+
+/// A request that got no response, reported by a transport other than
+/// `attohttpc`. `retryable` tells network failures, such as a refused or
+/// reset connection, apart from errors retrying can't fix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportErr {
+    message: String,
+    retryable: bool,
+}
+
+impl TransportErr {
+    pub fn new(message: &str, retryable: bool) -> TransportErr {
+        TransportErr {
+            message: message.into(),
+            retryable,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+}
+
+impl fmt::Display for TransportErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TransportErr {}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    method: Method,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    timeouts: Timeouts,
+}
+
+impl HttpRequest {
+    pub fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: Method::Get,
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// A PUT without a body
+    pub fn put(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: Method::Put,
+            ..HttpRequest::get(url)
+        }
+    }
+
+    /// A POST of `payload` serialized as JSON
+    pub fn post_json<T: Serialize + ?Sized>(url: &str, payload: &T) -> Result<HttpRequest> {
+        Ok(HttpRequest {
+            method: Method::Post,
+            url: url.to_string(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: serde_json::to_vec(payload)?,
+            timeouts: Timeouts::default(),
+        })
+    }
+
+    /// A POST of `fields` as an `application/x-www-form-urlencoded` body
+    pub fn post_form(url: &str, fields: &[(&str, &str)]) -> HttpRequest {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish();
+
+        HttpRequest {
+            method: Method::Post,
+            url: url.to_string(),
+            headers: vec![(
+                "Content-Type".to_string(),
+                "application/x-www-form-urlencoded".to_string(),
+            )],
+            body: body.into_bytes(),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> HttpRequest {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn basic_auth(self, username: &str, password: Option<&str>) -> HttpRequest {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password.unwrap_or("")));
+        self.header("Authorization", &format!("Basic {}", credentials))
+    }
+
+    pub fn bearer_auth(self, token: &str) -> HttpRequest {
+        self.header("Authorization", &format!("Bearer {}", token))
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> HttpRequest {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Sends a request and returns the response, whatever its status. Only
+/// failures to get a response at all are errors. Implementations should
+/// honour the request's `timeouts` and report running out of time as a
+/// `TimeoutErr`, and other failures as a `TransportErr` that says whether
+/// they are worth retrying.
+pub trait HttpClient: Send + Sync {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse>;
+
+    /// When requests sent through this client have to be done by, if ever.
+    /// Sources that also wait on something other than a request, like the
+    /// base identity `TokenFetcher` of impersonated credentials, stop waiting
+    /// then too.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// Response headers as pairs, skipping values that aren't valid UTF-8
+fn header_pairs<N, V>(headers: impl IntoIterator<Item = (N, V)>) -> Vec<(String, String)>
+where
+    N: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    headers
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let value = std::str::from_utf8(value.as_ref()).ok()?;
+            Some((name.as_ref().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The default client, a fresh `attohttpc` request per call
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AttoHttpClient;
+
+impl HttpClient for AttoHttpClient {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut builder = match request.method {
+            Method::Get => attohttpc::get(&request.url),
+            Method::Post => attohttpc::post(&request.url),
+            Method::Put => attohttpc::put(&request.url),
+        };
+        for (name, value) in &request.headers {
+            let name = attohttpc::header::HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+                GoErr::from(format!("invalid header name `{}`: {}", name, e).as_str())
+            })?;
+            builder = builder.try_header(name, value.as_str())?;
+        }
+        if let Some(timeout) = request.timeouts.connect {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = request.timeouts.read {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = request.timeouts.total {
+            builder = builder.timeout(timeout);
+        }
+
+        let url = request.url;
+        let timed_out = |e: attohttpc::Error| -> GoErr {
+            match e.kind() {
+                attohttpc::ErrorKind::Io(io)
+                    if matches!(
+                        io.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                    ) =>
+                {
+                    GoErr::from(TimeoutErr::request(&url))
+                }
+                _ => GoErr::from(e),
+            }
+        };
+
+        let response = builder.bytes(request.body).send().map_err(timed_out)?;
+        let status = response.status().as_u16();
+        let headers = header_pairs(response.headers());
+        Ok(HttpResponse {
+            status,
+            headers,
+            body: response.bytes().map_err(timed_out)?,
+        })
+    }
+}
+
+#[cfg(feature = "reqwest-client")]
+impl HttpClient for reqwest::blocking::Client {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut builder = match request.method {
+            Method::Get => self.get(&request.url),
+            Method::Post => self.post(&request.url),
+            Method::Put => self.put(&request.url),
+        };
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        // The blocking client only takes a total timeout per request; connect
+        // and read timeouts are set when building it
+        if let Some(timeout) = request.timeouts.total {
+            builder = builder.timeout(timeout);
+        }
+
+        let url = request.url;
+        let timed_out = |e: reqwest::Error| -> GoErr {
+            if e.is_timeout() {
+                GoErr::from(TimeoutErr::request(&url))
+            } else {
+                GoErr::from(e)
+            }
+        };
+
+        let response = builder.body(request.body).send().map_err(timed_out)?;
+        let status = response.status().as_u16();
+        let headers = header_pairs(response.headers());
+        Ok(HttpResponse {
+            status,
+            headers,
+            body: response.bytes().map_err(timed_out)?.to_vec(),
+        })
+    }
+}
+
+#[cfg(feature = "ureq-client")]
+impl HttpClient for ureq::Agent {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let timeouts = request.timeouts;
+        let url = request.url.clone();
+        let timed_out = |e: ureq::Error| -> GoErr {
+            match e {
+                ureq::Error::Timeout(_) => GoErr::from(TimeoutErr::request(&url)),
+                e => {
+                    let retryable = matches!(
+                        e,
+                        ureq::Error::Io(_)
+                            | ureq::Error::ConnectionFailed
+                            | ureq::Error::HostNotFound
+                    );
+                    GoErr::from(TransportErr::new(&e.to_string(), retryable))
+                }
+            }
+        };
+
+        let mut response = match request.method {
+            Method::Get => {
+                let mut builder = self.get(&request.url);
+                for (name, value) in &request.headers {
+                    builder = builder.header(name.as_str(), value.as_str());
+                }
+                builder
+                    .config()
+                    .http_status_as_error(false)
+                    .timeout_connect(timeouts.connect)
+                    .timeout_recv_response(timeouts.read)
+                    .timeout_recv_body(timeouts.read)
+                    .timeout_global(timeouts.total)
+                    .build()
+                    .call()
+            }
+            Method::Post | Method::Put => {
+                let mut builder = match request.method {
+                    Method::Put => self.put(&request.url),
+                    _ => self.post(&request.url),
+                };
+                for (name, value) in &request.headers {
+                    builder = builder.header(name.as_str(), value.as_str());
+                }
+                builder
+                    .config()
+                    .http_status_as_error(false)
+                    .timeout_connect(timeouts.connect)
+                    .timeout_recv_response(timeouts.read)
+                    .timeout_recv_body(timeouts.read)
+                    .timeout_global(timeouts.total)
+                    .build()
+                    .send(&request.body[..])
+            }
+        }
+        .map_err(timed_out)?;
+
+        let status = response.status().as_u16();
+        let headers = header_pairs(response.headers());
+        let body = response.body_mut().read_to_vec().map_err(timed_out)?;
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Applies `timeouts` to every request sent through `inner`, keeping any
+/// tighter limits a request already carries
+#[derive(Clone)]
+pub struct TimeoutClient {
+    inner: Arc<dyn HttpClient>,
+    timeouts: Timeouts,
+    deadline: Option<Instant>,
+}
+
+impl TimeoutClient {
+    pub fn new(inner: Arc<dyn HttpClient>, timeouts: Timeouts) -> TimeoutClient {
+        TimeoutClient {
+            inner,
+            timeouts,
+            deadline: None,
+        }
+    }
+
+    /// Also cuts the total timeout of each request down to what is left until
+    /// `deadline`, failing requests sent after it straight away
+    pub fn with_deadline(mut self, deadline: Instant) -> TimeoutClient {
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+impl HttpClient for TimeoutClient {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut timeouts = request.timeouts.tightest(self.timeouts);
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(GoErr::from(TimeoutErr::request(request.url())));
+            }
+            timeouts = timeouts.until(deadline);
+        }
+        self.inner.send(request.with_timeouts(timeouts))
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline.or_else(|| self.inner.deadline())
+    }
+}
+
+pub(crate) fn default_client() -> Arc<dyn HttpClient> {
+    Arc::new(AttoHttpClient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{self, mock, Matcher};
+
+    #[test]
+    fn form_post() {
+        let _mock = mock("POST", "/http_form")
+            .match_header("content-type", "application/x-www-form-urlencoded")
+            .match_header("authorization", "Basic aWQ6c2VjcmV0")
+            .match_body(Matcher::UrlEncoded("a b".to_string(), "c&d".to_string()))
+            .with_status(201)
+            .with_body("created")
+            .create();
+
+        let request = HttpRequest::post_form(
+            &format!("{}/http_form", mockito::server_url()),
+            &[("a b", "c&d")],
+        )
+        .basic_auth("id", Some("secret"));
+        let response = AttoHttpClient.send(request).unwrap();
+
+        assert_eq!(response.status(), 201);
+        assert!(response.is_success());
+        assert_eq!(response.text(), "created");
+    }
+
+    #[test]
+    fn json_post() {
+        let _mock = mock("POST", "/http_json")
+            .match_header("content-type", "application/json")
+            .match_header("authorization", "Bearer token")
+            .match_body(Matcher::Json(serde_json::json!({"a": "b"})))
+            .with_status(200)
+            .with_header("X-Flavor", "json")
+            .with_body(r#"{"c":"d"}"#)
+            .create();
+
+        let request = HttpRequest::post_json(
+            &format!("{}/http_json", mockito::server_url()),
+            &serde_json::json!({"a": "b"}),
+        )
+        .unwrap()
+        .bearer_auth("token");
+        let response = AttoHttpClient.send(request).unwrap();
+
+        assert_eq!(response.header("x-flavor"), Some("json"));
+        let body: serde_json::Value = response.json().unwrap();
+        assert_eq!(body["c"], "d");
+    }
+
+    #[test]
+    fn error_status_is_a_response() {
+        let _mock = mock("GET", "/http_missing")
+            .with_status(404)
+            .with_body("not found")
+            .create();
+
+        let request = HttpRequest::get(&format!("{}/http_missing", mockito::server_url()));
+        let response = AttoHttpClient.send(request).unwrap();
+
+        assert_eq!(response.status(), 404);
+        assert!(!response.is_success());
+    }
+
+    #[test]
+    fn read_timeout_is_a_timeout_error() {
+        // Connections are queued by the listener but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+
+        let client = TimeoutClient::new(
+            default_client(),
+            Timeouts::new().with_read(Duration::from_millis(100)),
+        );
+        let started = Instant::now();
+        let err = client.send(HttpRequest::get(&url)).unwrap_err();
+
+        assert!(err.is_timeout(), "{}", err);
+        assert!(err.is_retryable());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// A URL nothing listens on, so connecting to it is refused
+    #[cfg(any(feature = "reqwest-client", feature = "ureq-client"))]
+    fn refused_url() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/token", listener.local_addr().unwrap())
+    }
+
+    #[cfg(feature = "reqwest-client")]
+    #[test]
+    fn reqwest_connection_refused_is_retryable() {
+        let client = reqwest::blocking::Client::new();
+        let err = client.send(HttpRequest::get(&refused_url())).unwrap_err();
+
+        assert!(err.is_retryable(), "{}", err);
+        assert!(!err.is_timeout());
+    }
+
+    #[cfg(feature = "ureq-client")]
+    #[test]
+    fn ureq_connection_refused_is_retryable() {
+        let agent = ureq::Agent::new_with_defaults();
+        let err = agent.send(HttpRequest::get(&refused_url())).unwrap_err();
+
+        assert!(err.is_retryable(), "{}", err);
+        assert!(!err.is_timeout());
+    }
+
+    #[test]
+    fn tightest_timeouts() {
+        let a = Timeouts::new()
+            .with_connect(Duration::from_secs(1))
+            .with_total(Duration::from_secs(10));
+        let b = Timeouts::new()
+            .with_read(Duration::from_secs(2))
+            .with_total(Duration::from_secs(5));
+
+        let tightest = a.tightest(b);
+        assert_eq!(tightest.connect(), Some(Duration::from_secs(1)));
+        assert_eq!(tightest.read(), Some(Duration::from_secs(2)));
+        assert_eq!(tightest.total(), Some(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,467 @@
+//! OIDC ID tokens, needed to call services that authenticate the caller rather
+//! than authorize an access token, e.g. Cloud Run, Cloud Functions and IAP.
+//!
+//! ID tokens can be minted by service account keys, the metadata server and
+//! through impersonation. `IdTokenFetcher` caches them until they are within
+//! `refresh_buffer` of expiring.
+
+use crate::auth::TokenErr;
+use crate::credentials::Credentials;
+use crate::fetcher::RefreshLock;
+use crate::http::{default_client, AttoHttpClient, HttpClient, HttpRequest};
+use crate::impersonate::ImpersonatedCredentials;
+use crate::metadata::MetadataServer;
+use crate::signer;
+use crate::{form_body, GoErr, Result};
+
+use arc_swap::ArcSwapOption;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::str::FromStr;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+
+/// The claims of an ID token we care about
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdTokenClaims {
+    iss: String,
+    aud: String,
+    #[serde(default)]
+    sub: String,
+    exp: i64,
+    iat: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+impl IdTokenClaims {
+    pub fn issuer(&self) -> &str {
+        &self.iss
+    }
+
+    pub fn audience(&self) -> &str {
+        &self.aud
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.sub
+    }
+
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    pub fn email_verified(&self) -> Option<bool> {
+        self.email_verified
+    }
+
+    /// Unix timestamp at which the token expires
+    pub fn expires_at(&self) -> i64 {
+        self.exp
+    }
+
+    /// Unix timestamp at which the token was issued
+    pub fn issued_at(&self) -> i64 {
+        self.iat
+    }
+}
+
+/// An OIDC ID token along with its decoded claims. The signature is not
+/// verified, the token is meant to be sent to the service that verifies it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdToken {
+    token: String,
+    claims: IdTokenClaims,
+}
+
+impl std::fmt::Display for IdToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "IdToken: (aud: {}, expires_at: {})",
+            self.claims.aud, self.claims.exp
+        )
+    }
+}
+
+impl IdToken {
+    /// The encoded token, to be sent as `Authorization: Bearer <token>`
+    pub fn as_str(&self) -> &str {
+        &self.token
+    }
+
+    pub fn claims(&self) -> &IdTokenClaims {
+        &self.claims
+    }
+
+    /// Seconds until the token expires, 0 if it already has
+    pub fn expires_in(&self) -> u32 {
+        let seconds = self.claims.exp - OffsetDateTime::now_utc().unix_timestamp();
+        seconds.clamp(0, u32::MAX.into()) as u32
+    }
+}
+
+impl FromStr for IdToken {
+    type Err = GoErr;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let token = s.trim();
+        let payload = token
+            .split('.')
+            .nth(1)
+            .ok_or_else(|| GoErr::from("ID token is not a JWT"))?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .map_err(|e| GoErr::from(format!("invalid ID token payload: {}", e).as_str()))?;
+
+        Ok(IdToken {
+            token: token.to_string(),
+            claims: serde_json::from_slice(&payload)?,
+        })
+    }
+}
+
+/// Claims of the JWT a service account signs to request an ID token
+#[derive(Serialize)]
+struct IdTokenRequestClaims {
+    iss: String,
+    aud: String,
+    target_audience: String,
+    exp: i64,
+    iat: i64,
+}
+
+#[derive(Deserialize)]
+struct IdTokenResponse {
+    id_token: String,
+}
+
+/// Mints an ID token for `audience` with a service account key, by exchanging a
+/// JWT carrying `target_audience` at the token endpoint.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::credentials::Credentials;
+/// use goauth::id_token::get_id_token;
+///
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let id_token = get_id_token(&credentials, "https://my-service-abcdef-uc.a.run.app").unwrap();
+/// println!("{}", id_token);
+/// ```
+pub fn get_id_token(credentials: &Credentials, audience: &str) -> Result<IdToken> {
+    get_id_token_with_http_client(credentials, audience, &AttoHttpClient)
+}
+
+/// Like `get_id_token`, sending the request through `client`
+pub fn get_id_token_with_http_client(
+    credentials: &Credentials,
+    audience: &str,
+    client: &dyn HttpClient,
+) -> Result<IdToken> {
+    let iat = OffsetDateTime::now_utc().unix_timestamp();
+    let claims = IdTokenRequestClaims {
+        iss: credentials.iss(),
+        aud: credentials.token_uri(),
+        target_audience: audience.to_string(),
+        exp: iat + 3600,
+        iat,
+    };
+    let jwt_body = signer::encode_unpadded(&claims, credentials)?;
+
+    let request = HttpRequest::post_form(&credentials.token_uri(), &form_body(&jwt_body));
+    let response = client.send(request)?;
+
+    if response.is_success() {
+        IdToken::from_str(&response.json::<IdTokenResponse>()?.id_token)
+    } else {
+        let token_err = TokenErr::from_response(response.status(), &response.text());
+        Err(GoErr::from(token_err))
+    }
+}
+
+/// Anything that can mint ID tokens for an audience, cached by an
+/// `IdTokenFetcher`. Implemented by service account `Credentials`, the
+/// `MetadataServer` and `ImpersonatedCredentials`.
+pub trait IdTokenSource: Send + Sync {
+    /// Mints a new ID token for `audience`, sending any requests through `client`
+    fn get_id_token_with_http_client(
+        &self,
+        audience: &str,
+        client: &dyn HttpClient,
+    ) -> Result<IdToken>;
+}
+
+impl IdTokenSource for Credentials {
+    fn get_id_token_with_http_client(
+        &self,
+        audience: &str,
+        client: &dyn HttpClient,
+    ) -> Result<IdToken> {
+        get_id_token_with_http_client(self, audience, client)
+    }
+}
+
+/// Caches an ID token for a single audience, refreshing it once it is within
+/// `refresh_buffer` of its `exp` claim.
+pub struct IdTokenFetcher {
+    source: Arc<dyn IdTokenSource>,
+    audience: String,
+    token: ArcSwapOption<IdToken>,
+    refresh_lock: RefreshLock,
+    refresh_buffer: Duration,
+    http_client: Arc<dyn HttpClient>,
+}
+
+impl IdTokenFetcher {
+    pub fn with_service_account(
+        credentials: Credentials,
+        audience: &str,
+        refresh_buffer: Duration,
+    ) -> IdTokenFetcher {
+        IdTokenFetcher::with_source(Arc::new(credentials), audience, refresh_buffer)
+    }
+
+    pub fn with_metadata_server(
+        metadata: MetadataServer,
+        audience: &str,
+        refresh_buffer: Duration,
+    ) -> IdTokenFetcher {
+        IdTokenFetcher::with_source(Arc::new(metadata), audience, refresh_buffer)
+    }
+
+    pub fn with_impersonation(
+        credentials: ImpersonatedCredentials,
+        audience: &str,
+        refresh_buffer: Duration,
+    ) -> IdTokenFetcher {
+        IdTokenFetcher::with_source(Arc::new(credentials), audience, refresh_buffer)
+    }
+
+    /// Caches the ID tokens of any `IdTokenSource`, e.g. one of your own or a
+    /// fake in tests
+    pub fn with_source(
+        source: Arc<dyn IdTokenSource>,
+        audience: &str,
+        refresh_buffer: Duration,
+    ) -> IdTokenFetcher {
+        IdTokenFetcher {
+            source,
+            audience: audience.to_string(),
+            token: ArcSwapOption::from(None),
+            refresh_lock: RefreshLock::default(),
+            refresh_buffer,
+            http_client: default_client(),
+        }
+    }
+
+    /// Sends ID token requests through `client` instead of the default
+    /// `attohttpc` transport
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> IdTokenFetcher {
+        self.http_client = client;
+        self
+    }
+
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Returns the cached token unless it is within `refresh_buffer` of
+    /// expiring, in which case a new one is fetched and cached. Like those of
+    /// `TokenFetcher`, refreshes are single-flight: concurrent callers wait
+    /// for the one refreshing and are served the token it stored.
+    pub fn fetch_token(&self) -> Result<IdToken> {
+        if let Some(token) = self.valid_token() {
+            return Ok(token);
+        }
+
+        let _refreshing = self.refresh_lock.lock(None)?;
+        if let Some(token) = self.valid_token() {
+            return Ok(token);
+        }
+
+        let token = self
+            .source
+            .get_id_token_with_http_client(&self.audience, self.http_client.as_ref())?;
+        self.token.store(Some(Arc::new(token.clone())));
+        Ok(token)
+    }
+
+    /// The cached token, if it is not yet within `refresh_buffer` of expiring
+    fn valid_token(&self) -> Option<IdToken> {
+        let token = self.token.load_full()?;
+        let refresh_at = token.claims.exp - self.refresh_buffer.whole_seconds();
+        if OffsetDateTime::now_utc().unix_timestamp() < refresh_at {
+            Some(token.as_ref().clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use mockito::{self, mock, Matcher};
+
+    /// An unsigned JWT with the given claims, good enough as an ID token for
+    /// everything but verification
+    pub(crate) fn id_token(audience: &str, exp: i64) -> String {
+        let claims = serde_json::json!({
+            "iss": "https://accounts.google.com",
+            "aud": audience,
+            "sub": "1234",
+            "email": "sa@project.iam.gserviceaccount.com",
+            "email_verified": true,
+            "exp": exp,
+            "iat": exp - 3600,
+        });
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    #[test]
+    fn parses_claims() {
+        let token = IdToken::from_str(&id_token("https://example.com", 4102444800)).unwrap();
+        assert_eq!(token.claims().audience(), "https://example.com");
+        assert_eq!(
+            token.claims().email(),
+            Some("sa@project.iam.gserviceaccount.com")
+        );
+        assert_eq!(token.claims().expires_at(), 4102444800);
+        assert!(token.expires_in() > 0);
+    }
+
+    #[test]
+    fn rejects_non_jwt() {
+        assert!(IdToken::from_str("not a jwt").is_err());
+    }
+
+    #[test]
+    fn service_account_id_token() {
+        let mut credentials =
+            Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        credentials.token_uri = format!("{}/token", mockito::server_url());
+
+        let token = id_token("https://example.com", 4102444800);
+        let mock = mock("POST", "/token")
+            .match_body(Matcher::UrlEncoded(
+                "grant_type".to_string(),
+                "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string(),
+            ))
+            .with_status(200)
+            .with_body(serde_json::json!({ "id_token": token }).to_string())
+            .create();
+
+        let id_token = get_id_token(&credentials, "https://example.com").unwrap();
+        assert_eq!(id_token.as_str(), token);
+        mock.assert();
+    }
+
+    #[test]
+    fn fetcher_caches_until_refresh_buffer() {
+        let metadata = MetadataServer::with_host(&mockito::server_address().to_string());
+        let fetcher = IdTokenFetcher::with_metadata_server(
+            metadata,
+            "https://example.com",
+            Duration::new(60, 0),
+        );
+
+        let exp = OffsetDateTime::now_utc().unix_timestamp() + 3600;
+        let mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/identity",
+        )
+        .match_query(Matcher::UrlEncoded(
+            "audience".to_string(),
+            "https://example.com".to_string(),
+        ))
+        .with_status(200)
+        .with_body(id_token("https://example.com", exp))
+        .expect(1)
+        .create();
+
+        let first = fetcher.fetch_token().unwrap();
+        let second = fetcher.fetch_token().unwrap();
+        assert_eq!(first, second);
+        mock.assert();
+    }
+
+    #[test]
+    fn fetcher_refreshes_within_buffer() {
+        let metadata = MetadataServer::with_host(&mockito::server_address().to_string());
+        let fetcher = IdTokenFetcher::with_metadata_server(
+            metadata,
+            "https://refresh.example.com",
+            Duration::new(60, 0),
+        );
+
+        // Already within the refresh buffer when issued
+        let exp = OffsetDateTime::now_utc().unix_timestamp() + 30;
+        let mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/identity",
+        )
+        .match_query(Matcher::UrlEncoded(
+            "audience".to_string(),
+            "https://refresh.example.com".to_string(),
+        ))
+        .with_status(200)
+        .with_body(id_token("https://refresh.example.com", exp))
+        .expect(2)
+        .create();
+
+        fetcher.fetch_token().unwrap();
+        fetcher.fetch_token().unwrap();
+        mock.assert();
+    }
+
+    /// Counts the ID tokens it mints
+    struct CountingSource {
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl IdTokenSource for CountingSource {
+        fn get_id_token_with_http_client(
+            &self,
+            audience: &str,
+            _client: &dyn HttpClient,
+        ) -> Result<IdToken> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let exp = OffsetDateTime::now_utc().unix_timestamp() + 3600;
+            IdToken::from_str(&id_token(audience, exp))
+        }
+    }
+
+    #[test]
+    fn concurrent_fetches_refresh_once() {
+        let source = Arc::new(CountingSource {
+            calls: std::sync::atomic::AtomicUsize::new(0),
+        });
+        let fetcher = Arc::new(IdTokenFetcher::with_source(
+            source.clone(),
+            "https://example.com",
+            Duration::new(60, 0),
+        ));
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let fetcher = fetcher.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    fetcher.fetch_token().unwrap()
+                })
+            })
+            .collect();
+        let tokens: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert!(tokens.iter().all(|token| token == &tokens[0]));
+        assert_eq!(source.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
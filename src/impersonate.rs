@@ -0,0 +1,555 @@
+//! Service account impersonation through the IAM Credentials API, exchanges a
+//! token of one identity for a short lived token of a service account.
+
+use crate::auth::{Token, TokenErr};
+use crate::credentials::{from_typed_str, CredentialsFile, CredentialsType};
+use crate::http::{AttoHttpClient, HttpClient, HttpRequest, HttpResponse};
+use crate::id_token::{IdToken, IdTokenSource};
+use crate::scopes::Scope;
+use crate::source::TokenSource;
+use crate::{GoErr, Result};
+
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+pub(crate) const DEFAULT_LIFETIME_SECONDS: u32 = 3600;
+pub(crate) const IAM_CREDENTIALS_URL: &str =
+    "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts";
+
+/// The base identity needs the `cloud-platform` scope to call the IAM Credentials API
+pub(crate) const SOURCE_SCOPES: &[Scope] = &[Scope::CloudPlatform];
+
+/// Mints tokens for `target_principal` using tokens of a base identity, which
+/// needs the `roles/iam.serviceAccountTokenCreator` role on the target (or on
+/// the first of the `delegates`).
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::fetcher::TokenFetcher;
+/// use goauth::impersonate::ImpersonatedCredentials;
+/// use goauth::metadata::MetadataServer;
+/// use goauth::scopes::Scope;
+/// use std::sync::Arc;
+/// use time::Duration;
+///
+/// let source = TokenFetcher::with_metadata_server(MetadataServer::new(), Duration::new(60, 0));
+/// let credentials = ImpersonatedCredentials::new(
+///     Arc::new(source),
+///     "target@my-project.iam.gserviceaccount.com",
+///     &[Scope::DevStorageReadWrite],
+/// )
+/// .with_lifetime(Duration::new(600, 0));
+/// let fetcher = TokenFetcher::with_impersonation(credentials, Duration::new(60, 0));
+/// let token = fetcher.fetch_token().unwrap();
+/// ```
+pub struct ImpersonatedCredentials {
+    source: Arc<dyn TokenSource>,
+    // pub(crate) so this can be overriden in tests
+    pub(crate) url: String,
+    delegates: Vec<String>,
+    scopes: Vec<String>,
+    lifetime_seconds: u32,
+}
+
+impl ImpersonatedCredentials {
+    /// `source` provides tokens of the base identity, it should have been
+    /// created with the `cloud-platform` scope. Wrap it in a `TokenFetcher`
+    /// to cache its tokens.
+    pub fn new(source: Arc<dyn TokenSource>, target_principal: &str, scopes: &[Scope]) -> Self {
+        ImpersonatedCredentials::with_url(
+            source,
+            format!(
+                "{}/{}:generateAccessToken",
+                IAM_CREDENTIALS_URL, target_principal
+            ),
+            scopes,
+        )
+    }
+
+    fn with_url(source: Arc<dyn TokenSource>, url: String, scopes: &[Scope]) -> Self {
+        ImpersonatedCredentials {
+            source,
+            url,
+            delegates: Vec::new(),
+            scopes: scopes.iter().map(|scope| scope.url()).collect(),
+            lifetime_seconds: DEFAULT_LIFETIME_SECONDS,
+        }
+    }
+
+    /// Service accounts in the delegation chain, each needs the token creator
+    /// role on the next, the last one on the target
+    pub fn with_delegates(mut self, delegates: &[&str]) -> Self {
+        self.delegates = delegates
+            .iter()
+            .map(|delegate| {
+                if delegate.starts_with("projects/") {
+                    delegate.to_string()
+                } else {
+                    format!("projects/-/serviceAccounts/{}", delegate)
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// Lifetime of the minted tokens, an hour by default and up to 12 hours if
+    /// the organization policy allows it
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime_seconds = lifetime.whole_seconds().clamp(0, u32::MAX.into()) as u32;
+        self
+    }
+
+    /// The base identity token source
+    pub fn source(&self) -> &dyn TokenSource {
+        self.source.as_ref()
+    }
+
+    /// Fetches a token of the base identity and exchanges it for a token of
+    /// the target service account
+    pub fn get_token(&self) -> Result<Token> {
+        self.get_token_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `get_token`, sending the `generateAccessToken` request through
+    /// `client`. The base identity token is fetched through the source's
+    /// `get_token_with_http_client`, a `TokenFetcher` only takes the client's
+    /// deadline from it and fetches by its own client.
+    pub fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        let base_token = self.source.get_token_with_http_client(client)?;
+        generate_access_token_response(client.send(self.access_token_request(&base_token)?)?)
+    }
+
+    /// The `generateAccessToken` request, authenticated with `base_token`
+    pub(crate) fn access_token_request(&self, base_token: &Token) -> Result<HttpRequest> {
+        generate_access_token_request(
+            &self.url,
+            base_token,
+            &self.delegates,
+            &self.scopes,
+            self.lifetime_seconds,
+        )
+    }
+
+    /// The base identity token source, shared so it can be fetched from on
+    /// another thread
+    #[cfg(feature = "async")]
+    pub(crate) fn shared_source(&self) -> Arc<dyn TokenSource> {
+        self.source.clone()
+    }
+
+    /// Mints an ID token for `audience` as the target service account through
+    /// `generateIdToken`
+    pub fn get_id_token(&self, audience: &str) -> Result<IdToken> {
+        self.get_id_token_with_http_client(audience, &AttoHttpClient)
+    }
+
+    /// Like `get_id_token`, sending the `generateIdToken` request through
+    /// `client`. The base identity token is fetched like in
+    /// `get_token_with_http_client`, by the client's deadline if it has one.
+    pub fn get_id_token_with_http_client(
+        &self,
+        audience: &str,
+        client: &dyn HttpClient,
+    ) -> Result<IdToken> {
+        let base_token = self.source.get_token_with_http_client(client)?;
+        let url = format!(
+            "{}:generateIdToken",
+            self.url.trim_end_matches(":generateAccessToken")
+        );
+        let request = GenerateIdTokenRequest {
+            delegates: &self.delegates,
+            audience,
+            include_email: true,
+        };
+
+        let request =
+            HttpRequest::post_json(&url, &request)?.bearer_auth(base_token.access_token());
+        let response = client.send(request)?;
+
+        if !response.is_success() {
+            let token_err = TokenErr::from_response(response.status(), &response.text());
+            return Err(GoErr::endpoint("generateIdToken", token_err));
+        }
+
+        IdToken::from_str(&response.json::<GenerateIdTokenResponse>()?.token)
+    }
+}
+
+impl TokenSource for ImpersonatedCredentials {
+    fn get_token(&self) -> Result<Token> {
+        ImpersonatedCredentials::get_token(self)
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        ImpersonatedCredentials::get_token_with_http_client(self, client)
+    }
+}
+
+impl IdTokenSource for ImpersonatedCredentials {
+    fn get_id_token_with_http_client(
+        &self,
+        audience: &str,
+        client: &dyn HttpClient,
+    ) -> Result<IdToken> {
+        ImpersonatedCredentials::get_id_token_with_http_client(self, audience, client)
+    }
+}
+
+/// Credentials of type `impersonated_service_account`, as written by
+/// `gcloud auth application-default login --impersonate-service-account`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImpersonatedServiceAccountCredentials {
+    #[serde(rename = "type")]
+    t: String,
+    service_account_impersonation_url: String,
+    #[serde(default)]
+    delegates: Vec<String>,
+    source_credentials: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota_project_id: Option<String>,
+}
+
+impl ImpersonatedServiceAccountCredentials {
+    pub fn from_file(fp: &str) -> Result<Self> {
+        ImpersonatedServiceAccountCredentials::from_str(&fs::read_to_string(fp)?)
+    }
+
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+
+    /// The credentials of the base identity
+    pub fn source_credentials(&self) -> Result<CredentialsFile> {
+        CredentialsFile::from_str(&self.source_credentials.to_string())
+    }
+
+    /// Wraps the source credentials in a `TokenFetcher` and impersonates the
+    /// target service account with `scopes`
+    pub fn into_impersonated(
+        self,
+        scopes: &[Scope],
+        refresh_buffer: Duration,
+    ) -> Result<ImpersonatedCredentials> {
+        let source = self
+            .source_credentials()?
+            .into_token_fetcher(SOURCE_SCOPES, refresh_buffer)?;
+        let mut credentials = ImpersonatedCredentials::with_url(
+            Arc::new(source),
+            self.service_account_impersonation_url,
+            scopes,
+        );
+        credentials.delegates = self.delegates;
+        Ok(credentials)
+    }
+}
+
+impl FromStr for ImpersonatedServiceAccountCredentials {
+    type Err = GoErr;
+    fn from_str(s: &str) -> Result<Self, GoErr> {
+        from_typed_str(s, CredentialsType::ImpersonatedServiceAccount)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenRequest<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    delegates: &'a [String],
+    scope: &'a [String],
+    lifetime: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateIdTokenRequest<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    delegates: &'a [String],
+    audience: &'a str,
+    include_email: bool,
+}
+
+#[derive(Deserialize)]
+struct GenerateIdTokenResponse {
+    token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: String,
+}
+
+/// A `generateAccessToken` request to `url` authenticated with `base_token`
+pub(crate) fn generate_access_token_request(
+    url: &str,
+    base_token: &Token,
+    delegates: &[String],
+    scopes: &[String],
+    lifetime_seconds: u32,
+) -> Result<HttpRequest> {
+    let request = GenerateAccessTokenRequest {
+        delegates,
+        scope: scopes,
+        lifetime: format!("{}s", lifetime_seconds),
+    };
+
+    Ok(HttpRequest::post_json(url, &request)?.bearer_auth(base_token.access_token()))
+}
+
+/// Parses the token out of a `generateAccessToken` response
+pub(crate) fn generate_access_token_response(response: HttpResponse) -> Result<Token> {
+    if !response.is_success() {
+        let token_err = TokenErr::from_response(response.status(), &response.text());
+        return Err(GoErr::endpoint("generateAccessToken", token_err));
+    }
+
+    let response = response.json::<GenerateAccessTokenResponse>()?;
+    Ok(Token::new(
+        response.access_token,
+        "Bearer".to_string(),
+        expires_in(&response.expire_time)?,
+    ))
+}
+
+/// Seconds from now until the RFC 3339 timestamp `expire_time`
+pub(crate) fn expires_in(expire_time: &str) -> Result<u32> {
+    let expire_time = OffsetDateTime::parse(expire_time, &Rfc3339).map_err(|e| {
+        GoErr::from(format!("invalid expireTime `{}`: {}", expire_time, e).as_str())
+    })?;
+    let seconds = (expire_time - OffsetDateTime::now_utc()).whole_seconds();
+    Ok(seconds.clamp(0, u32::MAX.into()) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::AuthorizedUserCredentials;
+    use crate::fetcher::TokenFetcher;
+    use mockito::{self, mock, Matcher};
+
+    const GENERATE_ACCESS_TOKEN_PATH: &str =
+        "/v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateAccessToken";
+
+    fn source() -> Arc<dyn TokenSource> {
+        let mut credentials = AuthorizedUserCredentials::from_str(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        )
+        .unwrap();
+        credentials.token_uri = format!("{}/token", mockito::server_url());
+        Arc::new(TokenFetcher::with_authorized_user(
+            credentials,
+            Duration::new(0, 0),
+        ))
+    }
+
+    fn base_token_mock() -> mockito::Mock {
+        mock("POST", "/token")
+            .with_status(200)
+            .with_body(r#"{"access_token":"base_token","token_type":"Bearer","expires_in":3600}"#)
+            .create()
+    }
+
+    #[test]
+    fn impersonates_with_delegates_and_lifetime() {
+        let base = base_token_mock();
+        let impersonation = mock("POST", GENERATE_ACCESS_TOKEN_PATH)
+            .match_header("Authorization", "Bearer base_token")
+            .match_body(Matcher::Json(serde_json::json!({
+                "delegates": ["projects/-/serviceAccounts/delegate@project.iam.gserviceaccount.com"],
+                "scope": [Scope::DevStorageReadWrite.url()],
+                "lifetime": "600s"
+            })))
+            .with_status(200)
+            .with_body(r#"{"accessToken":"impersonated","expireTime":"2099-01-01T00:00:00Z"}"#)
+            .create();
+
+        let mut credentials = ImpersonatedCredentials::new(
+            source(),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::DevStorageReadWrite],
+        )
+        .with_delegates(&["delegate@project.iam.gserviceaccount.com"])
+        .with_lifetime(Duration::new(600, 0));
+        credentials.url = format!("{}{}", mockito::server_url(), GENERATE_ACCESS_TOKEN_PATH);
+
+        let token = credentials.get_token().unwrap();
+        assert_eq!(token.access_token(), "impersonated");
+        assert_eq!(token.token_type(), "Bearer");
+        base.assert();
+        impersonation.assert();
+    }
+
+    #[test]
+    fn any_token_source_as_base_identity() {
+        use crate::retry::tests::ScriptedClient;
+        use crate::source::tests::FakeSource;
+
+        let source = Arc::new(FakeSource::new());
+        let credentials = ImpersonatedCredentials::new(
+            source.clone(),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::DevStorageReadWrite],
+        );
+        // The base token request of the fake source, then `generateAccessToken`
+        let client = ScriptedClient::new(&[
+            (200, ""),
+            (
+                200,
+                r#"{"accessToken":"impersonated","expireTime":"2099-01-01T00:00:00Z"}"#,
+            ),
+        ]);
+
+        let token = credentials.get_token_with_http_client(&client).unwrap();
+        assert_eq!(token.access_token(), "impersonated");
+        assert_eq!(source.calls(), 1);
+        assert_eq!(client.attempts(), 2);
+    }
+
+    #[test]
+    fn impersonated_id_token() {
+        let _base = base_token_mock();
+        let id_token = crate::id_token::tests::id_token("https://example.com", 4102444800);
+        let mock = mock(
+            "POST",
+            "/v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateIdToken",
+        )
+        .match_header("Authorization", "Bearer base_token")
+        .match_body(Matcher::Json(serde_json::json!({
+            "audience": "https://example.com",
+            "includeEmail": true
+        })))
+        .with_status(200)
+        .with_body(serde_json::json!({ "token": id_token }).to_string())
+        .create();
+
+        let mut credentials = ImpersonatedCredentials::new(
+            source(),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::CloudPlatform],
+        );
+        credentials.url = format!("{}{}", mockito::server_url(), GENERATE_ACCESS_TOKEN_PATH);
+
+        let token = credentials.get_id_token("https://example.com").unwrap();
+        assert_eq!(token.claims().audience(), "https://example.com");
+        mock.assert();
+    }
+
+    #[test]
+    fn deadline_bounds_the_base_token() {
+        // Connections are queued by the listener but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut credentials = AuthorizedUserCredentials::from_str(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        )
+        .unwrap();
+        credentials.token_uri = format!("http://{}/token", listener.local_addr().unwrap());
+        let source = TokenFetcher::with_authorized_user(credentials, Duration::new(0, 0));
+        let credentials = ImpersonatedCredentials::new(
+            Arc::new(source),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::DevStorageReadWrite],
+        );
+        let fetcher = TokenFetcher::with_impersonation(credentials, Duration::new(0, 0));
+
+        let started = std::time::Instant::now();
+        let err = fetcher
+            .fetch_token_with_deadline(started + std::time::Duration::from_millis(200))
+            .unwrap_err();
+
+        assert!(err.is_timeout());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn deadline_bounds_the_base_token_of_an_id_token() {
+        use crate::http::{TimeoutClient, Timeouts};
+
+        // Connections are queued by the listener but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut credentials = AuthorizedUserCredentials::from_str(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        )
+        .unwrap();
+        credentials.token_uri = format!("http://{}/token", listener.local_addr().unwrap());
+        let source = TokenFetcher::with_authorized_user(credentials, Duration::new(0, 0));
+        let credentials = ImpersonatedCredentials::new(
+            Arc::new(source),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::CloudPlatform],
+        );
+
+        let started = std::time::Instant::now();
+        let client = TimeoutClient::new(Arc::new(AttoHttpClient), Timeouts::new())
+            .with_deadline(started + std::time::Duration::from_millis(200));
+        let err = credentials
+            .get_id_token_with_http_client("https://example.com", &client)
+            .unwrap_err();
+
+        assert!(err.is_timeout());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn permission_denied() {
+        let _base = base_token_mock();
+        let _impersonation = mock("POST", GENERATE_ACCESS_TOKEN_PATH)
+            .with_status(403)
+            .with_body(r#"{"error":{"code":403,"message":"Permission denied","status":"PERMISSION_DENIED"}}"#)
+            .create();
+
+        let mut credentials = ImpersonatedCredentials::new(
+            source(),
+            "target@project.iam.gserviceaccount.com",
+            &[Scope::DevStorageReadWrite],
+        );
+        credentials.url = format!("{}{}", mockito::server_url(), GENERATE_ACCESS_TOKEN_PATH);
+
+        assert!(credentials
+            .get_token()
+            .unwrap_err()
+            .to_string()
+            .contains("403"));
+    }
+
+    #[test]
+    fn impersonated_service_account_file() {
+        let _base = base_token_mock();
+        let impersonation = mock("POST", GENERATE_ACCESS_TOKEN_PATH)
+            .match_header("Authorization", "Bearer base_token")
+            .match_body(Matcher::PartialJson(serde_json::json!({
+                "delegates": ["projects/-/serviceAccounts/delegate@project.iam.gserviceaccount.com"]
+            })))
+            .with_status(200)
+            .with_body(r#"{"accessToken":"impersonated","expireTime":"2099-01-01T00:00:00Z"}"#)
+            .create();
+
+        let file = serde_json::json!({
+            "type": "impersonated_service_account",
+            "service_account_impersonation_url": format!("{}{}", mockito::server_url(), GENERATE_ACCESS_TOKEN_PATH),
+            "delegates": ["projects/-/serviceAccounts/delegate@project.iam.gserviceaccount.com"],
+            "source_credentials": {
+                "type": "authorized_user",
+                "client_id": "id",
+                "client_secret": "secret",
+                "refresh_token": "refresh",
+                "token_uri": format!("{}/token", mockito::server_url())
+            }
+        });
+
+        let credentials = ImpersonatedServiceAccountCredentials::from_str(&file.to_string())
+            .unwrap()
+            .into_impersonated(&[Scope::DevStorageReadWrite], Duration::new(0, 0))
+            .unwrap();
+
+        assert_eq!(
+            credentials.get_token().unwrap().access_token(),
+            "impersonated"
+        );
+        impersonation.assert();
+    }
+}
@@ -5,17 +5,44 @@ extern crate serde_derive;
 #[macro_use]
 extern crate doc_comment;
 
+pub mod adc;
+#[cfg(feature = "async")]
+pub mod asynchronous;
 pub mod auth;
+mod aws;
 pub mod credentials;
+pub mod delegation;
+mod executable;
+pub mod external_account;
 pub mod fetcher;
+pub mod http;
+pub mod id_token;
+pub mod impersonate;
+#[cfg(feature = "rustcrypto")]
+pub mod jwt;
+pub mod metadata;
+pub mod retry;
 pub mod scopes;
+pub mod self_signed;
+pub mod signer;
+pub mod source;
+
+#[cfg(not(any(feature = "openssl", feature = "rustcrypto")))]
+compile_error!("a signing backend is needed, enable the `openssl` or the `rustcrypto` feature");
 
 use auth::{JwtClaims, Token};
+#[cfg(feature = "openssl")]
 use credentials::Credentials;
+use http::{AttoHttpClient, HttpClient, HttpRequest, HttpResponse};
+#[cfg(feature = "openssl")]
+use http::{TimeoutClient, Timeouts};
+#[cfg(feature = "openssl")]
+use retry::{RetryPolicy, RetryingClient};
 
+#[cfg(feature = "openssl")]
 pub use smpl_jwt::Jwt;
-use std::str::FromStr;
 
+#[cfg(feature = "openssl")]
 const DEFAULT_URL: &str = "https://www.googleapis.com/oauth2/v4/token";
 
 fn form_body(body: &str) -> Vec<(&str, &str)> {
@@ -25,15 +52,101 @@ fn form_body(body: &str) -> Vec<(&str, &str)> {
     ]
 }
 
+/// The errors of `smpl_jwt`, which only the APIs of the `openssl` feature use
+#[cfg(feature = "openssl")]
+type JwtErr = smpl_jwt::JwtErr;
+#[cfg(not(feature = "openssl"))]
+type JwtErr = std::convert::Infallible;
+
 simpl::err!(GoErr,
 {
     Io@std::io::Error;
-    Jwt@smpl_jwt::JwtErr;
+    Jwt@Box<JwtErr>;
     Json@serde_json::Error;
     Reqwest@attohttpc::Error;
-    Token@auth::TokenErr;
+    Token@Box<auth::TokenErr>;
+    Timeout@http::TimeoutErr;
+    Transport@http::TransportErr;
 });
 
+impl GoErr {
+    /// The error response of a token or credentials endpoint, when that is
+    /// what failed
+    pub fn token_err(&self) -> Option<&auth::TokenErr> {
+        match &self.source {
+            Some(Errs::Token(token_err)) => Some(token_err.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status of the failed response, if there was one
+    pub fn status(&self) -> Option<u16> {
+        self.token_err().and_then(|token_err| token_err.status())
+    }
+
+    /// Network failures, server errors and rate limiting, which may go away
+    /// on their own
+    pub fn is_retryable(&self) -> bool {
+        match &self.source {
+            Some(Errs::Token(token_err)) => token_err.is_retryable(),
+            Some(Errs::Reqwest(e)) => matches!(e.kind(), attohttpc::ErrorKind::Io(_)),
+            Some(Errs::Timeout(_)) => true,
+            Some(Errs::Transport(e)) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// A request, or a wait for a token, that ran out of time
+    pub fn is_timeout(&self) -> bool {
+        matches!(&self.source, Some(Errs::Timeout(_)))
+    }
+
+    /// Errors that won't go away until the credentials, their scopes or their
+    /// permissions are fixed, e.g. `invalid_grant` or a `403`
+    pub fn is_auth_misconfiguration(&self) -> bool {
+        self.token_err()
+            .map(|token_err| token_err.is_auth_misconfiguration())
+            .unwrap_or(false)
+    }
+
+    /// An error of an endpoint described by `context`, keeping the status and
+    /// body of its response
+    pub(crate) fn endpoint(context: &str, token_err: auth::TokenErr) -> GoErr {
+        GoErr {
+            description: Some(format!(
+                "{} returned {}: {}",
+                context,
+                token_err.status().unwrap_or_default(),
+                token_err.body()
+            )),
+            data: None,
+            source: Some(Errs::Token(Box::new(token_err))),
+        }
+    }
+}
+
+// The two largest sources are boxed to keep `Result<T, GoErr>` small
+#[cfg(feature = "openssl")]
+impl From<smpl_jwt::JwtErr> for GoErr {
+    fn from(e: smpl_jwt::JwtErr) -> GoErr {
+        GoErr::from(Box::new(e))
+    }
+}
+
+impl From<auth::TokenErr> for GoErr {
+    fn from(e: auth::TokenErr) -> GoErr {
+        GoErr::from(Box::new(e))
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for GoErr {
+    fn from(e: reqwest::Error) -> GoErr {
+        let retryable = e.is_connect() || e.is_request() || e.is_body();
+        GoErr::from(http::TransportErr::new(&e.to_string(), retryable))
+    }
+}
+
 /// Get Token which can be used to authenticate further request
 /// ### Example
 ///
@@ -69,23 +182,28 @@ simpl::err!(GoErr,
 /// }
 ///
 /// ```
-#[allow(clippy::result_large_err)]
+#[cfg(feature = "openssl")]
 pub fn get_token_legacy(jwt: &Jwt<JwtClaims>, url: Option<&str>) -> Result<Token> {
     let final_jwt = jwt.finalize()?;
     let request_body = form_body(&final_jwt);
-    let response = attohttpc::post(url.unwrap_or(DEFAULT_URL))
-        .form(&request_body)?
-        .send()?;
+    let request = HttpRequest::post_form(url.unwrap_or(DEFAULT_URL), &request_body);
+    let response = AttoHttpClient.send(request)?;
 
-    Token::from_str(&response.text()?)
+    token_response(response)
 }
 
-#[allow(clippy::result_large_err)]
+#[cfg(feature = "openssl")]
 pub fn get_token_as_string_legacy(jwt: &Jwt<JwtClaims>, url: Option<&str>) -> Result<String> {
     Ok(serde_json::to_string(&get_token_legacy(jwt, url)?)?)
 }
 
 /// Async get Token which can be used to authenticate further request
+///
+/// Deprecated: the key and header of `jwt` are ignored, only its claims are
+/// used. They are signed with the private key of `credentials`, with its
+/// `private_key_id` as the `kid` of the header. Use `get_token_with_signer`,
+/// which takes the claims and signer that are actually used.
+///
 /// ### Example
 ///
 /// ```rust no_run
@@ -120,31 +238,207 @@ pub fn get_token_as_string_legacy(jwt: &Jwt<JwtClaims>, url: Option<&str>) -> Re
 /// }
 ///
 /// ```
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `get_token_with_signer(jwt.body(), &credentials)`"
+)]
 pub fn get_token(jwt: &Jwt<JwtClaims>, credentials: &Credentials) -> Result<Token> {
-    get_token_with_client(jwt, credentials)
+    exchange_jwt(jwt, credentials, &AttoHttpClient)
 }
 
+/// The same as `get_token`, the key of `jwt` is ignored
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `get_token_with_signer(jwt.body(), &credentials)`"
+)]
 pub fn get_token_with_client(jwt: &Jwt<JwtClaims>, credentials: &Credentials) -> Result<Token> {
-    let jwt_body = jwt.finalize()?;
+    exchange_jwt(jwt, credentials, &AttoHttpClient)
+}
+
+/// Like `get_token`, retrying transient failures according to `retry`. The key
+/// of `jwt` is ignored, as it is by `get_token`.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::auth::JwtClaims;
+/// use goauth::credentials::Credentials;
+/// use goauth::get_token_with_retry;
+/// use goauth::retry::RetryPolicy;
+/// use goauth::scopes::Scope;
+/// use smpl_jwt::Jwt;
+/// use std::time::Duration;
+///
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let claims = JwtClaims::new(credentials.iss(),
+///                             &[Scope::DevStorageReadWrite],
+///                             credentials.token_uri(),
+///                             None, None);
+/// let jwt = Jwt::new(claims, credentials.rsa_key().unwrap(), None);
+///
+/// let retry = RetryPolicy::new().with_max_attempts(5).with_deadline(Duration::from_secs(10));
+/// let token = get_token_with_retry(&jwt, &credentials, &retry).unwrap();
+/// ```
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `get_token_with_signer_and_http_client` with a `RetryingClient`"
+)]
+pub fn get_token_with_retry(
+    jwt: &Jwt<JwtClaims>,
+    credentials: &Credentials,
+    retry: &RetryPolicy,
+) -> Result<Token> {
+    let client = RetryingClient::new(http::default_client(), retry.clone());
+
+    exchange_jwt(jwt, credentials, &client)
+}
+
+/// Like `get_token`, bounding the request by `timeouts`. A request that
+/// runs out of time fails with an error for which `is_timeout` is true. The
+/// key of `jwt` is ignored, as it is by `get_token`.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::auth::JwtClaims;
+/// use goauth::credentials::Credentials;
+/// use goauth::get_token_with_timeouts;
+/// use goauth::http::Timeouts;
+/// use goauth::scopes::Scope;
+/// use smpl_jwt::Jwt;
+/// use std::time::Duration;
+///
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let claims = JwtClaims::new(credentials.iss(),
+///                             &[Scope::DevStorageReadWrite],
+///                             credentials.token_uri(),
+///                             None, None);
+/// let jwt = Jwt::new(claims, credentials.rsa_key().unwrap(), None);
+///
+/// let timeouts = Timeouts::new()
+///     .with_connect(Duration::from_secs(2))
+///     .with_total(Duration::from_secs(10));
+/// match get_token_with_timeouts(&jwt, &credentials, &timeouts) {
+///     Ok(token) => println!("{}", token),
+///     Err(e) if e.is_timeout() => eprintln!("token endpoint too slow: {}", e),
+///     Err(e) => panic!("{}", e),
+/// }
+/// ```
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `get_token_with_signer_and_http_client` with a `TimeoutClient`"
+)]
+pub fn get_token_with_timeouts(
+    jwt: &Jwt<JwtClaims>,
+    credentials: &Credentials,
+    timeouts: &Timeouts,
+) -> Result<Token> {
+    let client = TimeoutClient::new(http::default_client(), *timeouts);
+
+    exchange_jwt(jwt, credentials, &client)
+}
+
+/// Exchanges a JWT for `claims`, signed by `signer`, at the token endpoint in
+/// the `aud` of the claims
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::auth::JwtClaims;
+/// use goauth::get_token_with_signer;
+/// use goauth::scopes::Scope;
+/// use goauth::signer::RsaSigner;
+///
+/// let pem = std::fs::read_to_string("random_rsa_for_testing").unwrap();
+/// let signer = RsaSigner::from_pem(&pem).unwrap().with_key_id("0123456789abcdef");
+/// let claims = JwtClaims::new("service@project.iam.gserviceaccount.com".to_string(),
+///                             &[Scope::DevStorageReadWrite],
+///                             "https://oauth2.googleapis.com/token".to_string(),
+///                             None, None);
+/// let token = get_token_with_signer(&claims, &signer).unwrap();
+/// ```
+pub fn get_token_with_signer(claims: &JwtClaims, signer: &dyn signer::Signer) -> Result<Token> {
+    get_token_with_signer_and_http_client(claims, signer, &AttoHttpClient)
+}
+
+/// Like `get_token_with_signer`, sending the request through `client`, e.g.
+/// a `TimeoutClient` or `RetryingClient`. Only the token request goes through
+/// `client`, a signer that makes requests of its own, like `IamSigner`, is
+/// given its client separately.
+pub fn get_token_with_signer_and_http_client(
+    claims: &JwtClaims,
+    signer: &dyn signer::Signer,
+    client: &dyn HttpClient,
+) -> Result<Token> {
+    let jwt_body = signer::encode(claims, signer)?;
+    let request_body = form_body(&jwt_body);
+
+    post_token_request(client, claims.audience(), &request_body)
+}
+
+/// Like `get_token`, sending the request through `client` instead of the
+/// default `attohttpc` transport. The key of `jwt` is ignored, as it is by
+/// `get_token`.
+#[cfg(feature = "openssl")]
+#[deprecated(
+    since = "0.17.0",
+    note = "the key of the `Jwt` is ignored, use `get_token_with_signer_and_http_client(jwt.body(), &credentials, client)`"
+)]
+pub fn get_token_with_http_client(
+    jwt: &Jwt<JwtClaims>,
+    credentials: &Credentials,
+    client: &dyn HttpClient,
+) -> Result<Token> {
+    exchange_jwt(jwt, credentials, client)
+}
+
+/// Signs the claims of `jwt` with `credentials` and exchanges the result at
+/// the token endpoint of the credentials
+#[cfg(feature = "openssl")]
+fn exchange_jwt(
+    jwt: &Jwt<JwtClaims>,
+    credentials: &Credentials,
+    client: &dyn HttpClient,
+) -> Result<Token> {
+    let jwt_body = signer::encode(jwt.body(), credentials)?;
 
-    get_token_with_client_and_body(jwt_body, credentials)
+    get_token_with_client_and_body(jwt_body, credentials, client)
 }
 
+#[cfg(feature = "openssl")]
 pub(crate) fn get_token_with_client_and_body(
     jwt_body: String,
     credentials: &Credentials,
+    client: &dyn HttpClient,
 ) -> Result<Token> {
     let request_body = form_body(&jwt_body);
 
-    let response = attohttpc::post(credentials.token_uri())
-        .form(&request_body)?
-        .send()?;
+    post_token_request(client, &credentials.token_uri(), &request_body)
+}
+
+/// Posts a form to a token endpoint, parsing either a `Token` or a `TokenErr`
+/// out of the response
+pub(crate) fn post_token_request(
+    client: &dyn HttpClient,
+    url: &str,
+    request_body: &[(&str, &str)],
+) -> Result<Token> {
+    let response = client.send(HttpRequest::post_form(url, request_body))?;
+
+    token_response(response)
+}
 
-    if response.status().is_success() {
-        let token = response.json::<Token>()?;
+/// Parses a token endpoint response into a `Token`, or a `TokenErr` on failure
+pub(crate) fn token_response(response: HttpResponse) -> Result<Token> {
+    if response.is_success() {
+        let token = serde_json::from_slice::<Token>(response.body())?;
         Ok(token)
     } else {
-        let token_err = response.json::<auth::TokenErr>()?;
+        let token_err = auth::TokenErr::from_response(response.status(), &response.text());
         Err(GoErr::from(token_err))
     }
 }
@@ -155,6 +449,7 @@ mod tests {
 
     doctest!("../README.md");
 
+    #[cfg(feature = "openssl")]
     #[test]
     fn test_jwt_encode() {
         use auth::JwtClaims;
@@ -180,6 +475,7 @@ mod tests {
         assert_eq!(jwt.finalize().unwrap(), "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJpc3MiOiJzb21lX2lzcyIsInNjb3BlIjoiaHR0cHM6Ly93d3cuZ29vZ2xlYXBpcy5jb20vYXV0aC9kZXZzdG9yYWdlLnJlYWRfd3JpdGUiLCJhdWQiOiJodHRwczovL3d3dy5nb29nbGVhcGlzLmNvbS9vYXV0aDIvdjQvdG9rZW4iLCJleHAiOjE0ODIzMjA5ODUsImlhdCI6MTQ4MjMxNzM4NX0=.BldQozpzNYnLnYWBbqwAWY1j2hPDD3oVY9EOG0eRJN77sC4ZInEyGJT5eXLD39C726TdrEVCHmvhKBJFmaFL2BXNto69_v8lz-3oGnFL5FkUr4RRpukd_6tj7-RZzx15LIzdTqzKfAUlqWoZUdze8Fcd1NJ6w1g49CCghvN_eryvecALpjnHoBkKlIXnSm_udiSf26cYWvCikmW5g8nUqAduFsIYfR-4LMwyUfYH1hNC64SRsfLH9bL4-tyeaoUCv5MXTIhxrJbrhQy3TEOSc5didDrMoYNUu_qjJvxBQbq1Um1W1SpyvSd4eVJn18xZcOmCnoE73RDZcxT5hDpaRQ==");
     }
 
+    #[cfg(feature = "openssl")]
     #[test]
     fn test_jwt_encode_multiple_scopes() {
         use auth::JwtClaims;
@@ -205,6 +501,7 @@ mod tests {
         assert_eq!(jwt.finalize().unwrap(), "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJpc3MiOiJzb21lX2lzcyIsInNjb3BlIjoiaHR0cHM6Ly93d3cuZ29vZ2xlYXBpcy5jb20vYXV0aC9kZXZzdG9yYWdlLnJlYWRfd3JpdGUgaHR0cHM6Ly93d3cuZ29vZ2xlYXBpcy5jb20vYXV0aC9kZXZzdG9yYWdlLnJlYWRfb25seSIsImF1ZCI6Imh0dHBzOi8vd3d3Lmdvb2dsZWFwaXMuY29tL29hdXRoMi92NC90b2tlbiIsImV4cCI6MTQ4MjMyMDk4NSwiaWF0IjoxNDgyMzE3Mzg1fQ==.BUWdKTcaN11Y8X6MV2KLBnB1JBeEq-7AvepT_1gjgG7g2out7zlxgQmK-RxhjZBDr0UeIThtB-afaTxOMEEhtrxXW4Moz8p8SIC01j6a8HS49xCfTCPsyqnrXRcOJwEaI6LNAht_yJ-H5Q6ql0bk1zdtZyVBVMbQ94ZWAeZqQcUWQqIGsr11W-6hueFX8fIDCTGHsmsz0VuyQEpwfSFdeO_7LrLjfmUMN8GGzldT0b8v7-SwK7xzEl4DrReRpDW0s0Aeq16WZzFd-dzaFox5xWWJq3xcNl_tR3eTk7DW8s95bMQDmNa4CGFmGha1PrUnVJRW9p9wqFO90t1G7xD97A==");
     }
 
+    #[cfg(feature = "openssl")]
     #[test]
     fn get_token_test() {
         //  This test will always pass, output is logged via debug macro
@@ -233,4 +530,106 @@ mod tests {
             Err(e) => println!("{}", e),
         };
     }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn get_token_legacy_error_response() {
+        use mockito::mock;
+        use scopes::Scope;
+        use smpl_jwt::{Jwt, RSAKey};
+
+        let _mock = mock("POST", "/legacy_invalid_grant")
+            .with_status(400)
+            .with_body(r#"{"error":"invalid_grant","error_description":"Invalid JWT Signature."}"#)
+            .create();
+
+        let url = format!("{}/legacy_invalid_grant", mockito::server_url());
+        let claims = JwtClaims::new(
+            "some_iss".to_string(),
+            &[Scope::DevStorageReadWrite],
+            url.clone(),
+            None,
+            None,
+        );
+        let key = RSAKey::from_pem("random_rsa_for_testing").unwrap();
+        let jwt = Jwt::new(claims, key, None);
+
+        let err = get_token_legacy(&jwt, Some(&url)).unwrap_err();
+        assert_eq!(err.status(), Some(400));
+        assert!(err.is_auth_misconfiguration());
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    #[allow(deprecated)]
+    fn get_token_sends_private_key_id() {
+        use mockito::{mock, Matcher};
+        use scopes::Scope;
+
+        let mut credentials =
+            Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        credentials.token_uri = format!("{}/get_token_kid", mockito::server_url());
+        let claims = JwtClaims::new(
+            credentials.iss(),
+            &[Scope::DevStorageReadWrite],
+            credentials.token_uri(),
+            None,
+            None,
+        );
+        let jwt = Jwt::new(claims, credentials.rsa_key().unwrap(), None);
+
+        // The encoded `{"alg":"RS256","typ":"JWT","kid":"dummy"}`
+        let _mock = mock("POST", "/get_token_kid")
+            .match_body(Matcher::Regex(
+                "assertion=eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6ImR1bW15In0".to_string(),
+            ))
+            .with_status(200)
+            .with_body(r#"{"access_token":"kid_token","token_type":"Bearer","expires_in":3600}"#)
+            .create();
+
+        let token = get_token(&jwt, &credentials).unwrap();
+        assert_eq!(token.access_token(), "kid_token");
+    }
+
+    #[test]
+    fn signer_token_through_http_client() {
+        use retry::tests::ScriptedClient;
+        use scopes::Scope;
+
+        let pem = std::fs::read_to_string("random_rsa_for_testing").unwrap();
+        let signer = signer::RsaSigner::from_pem(&pem).unwrap();
+        let claims = JwtClaims::new(
+            "some_iss".to_string(),
+            &[Scope::DevStorageReadWrite],
+            "https://oauth2.googleapis.com/token".to_string(),
+            None,
+            None,
+        );
+        let client = ScriptedClient::new(&[(
+            200,
+            r#"{"access_token":"signed","token_type":"Bearer","expires_in":3600}"#,
+        )]);
+
+        let token = get_token_with_signer_and_http_client(&claims, &signer, &client).unwrap();
+        assert_eq!(token.access_token(), "signed");
+        assert_eq!(client.attempts(), 1);
+    }
+
+    #[test]
+    fn non_json_error_response() {
+        use mockito::mock;
+
+        let _mock = mock("POST", "/bad_gateway")
+            .with_status(502)
+            .with_body("<html>Bad Gateway</html>")
+            .create();
+
+        let url = format!("{}/bad_gateway", mockito::server_url());
+        let err = post_token_request(&AttoHttpClient, &url, &[]).unwrap_err();
+
+        assert_eq!(err.status(), Some(502));
+        assert_eq!(err.token_err().unwrap().body(), "<html>Bad Gateway</html>");
+        assert!(err.is_retryable());
+        assert!(!err.is_auth_misconfiguration());
+    }
 }
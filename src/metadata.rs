@@ -0,0 +1,307 @@
+//! Fetches access tokens from the GCE metadata server, which is available to
+//! workloads running on Compute Engine, GKE (including Workload Identity) and
+//! Cloud Run. No key file is needed, the identity is that of the service
+//! account attached to the workload.
+
+use crate::auth::{Token, TokenErr};
+use crate::http::{AttoHttpClient, HttpClient, HttpRequest, Timeouts};
+use crate::id_token::{IdToken, IdTokenSource};
+use crate::scopes::Scope;
+use crate::source::TokenSource;
+use crate::{GoErr, Result};
+
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Overrides the metadata server host, e.g. `localhost:8080` for a local emulator
+pub const METADATA_HOST_ENV_VAR: &str = "GCE_METADATA_HOST";
+const DEFAULT_METADATA_HOST: &str = "169.254.169.254";
+const DEFAULT_ACCOUNT: &str = "default";
+pub(crate) const METADATA_FLAVOR: &str = "Metadata-Flavor";
+pub(crate) const METADATA_FLAVOR_VALUE: &str = "Google";
+
+/// How long to wait for the metadata server when probing for it, it answers
+/// within a few milliseconds when present.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Credentials backed by the metadata server.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::fetcher::TokenFetcher;
+/// use goauth::metadata::MetadataServer;
+/// use time::Duration;
+///
+/// let metadata = MetadataServer::new().with_account("worker@my-project.iam.gserviceaccount.com");
+/// let fetcher = TokenFetcher::with_metadata_server(metadata, Duration::new(60, 0));
+/// let token = fetcher.fetch_token().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetadataServer {
+    host: String,
+    account: String,
+    scopes: Vec<String>,
+}
+
+impl Default for MetadataServer {
+    fn default() -> Self {
+        MetadataServer::new()
+    }
+}
+
+impl MetadataServer {
+    /// Uses the host from `GCE_METADATA_HOST` if set, `169.254.169.254` otherwise,
+    /// and the default service account of the instance.
+    pub fn new() -> MetadataServer {
+        let host =
+            env::var(METADATA_HOST_ENV_VAR).unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_string());
+        MetadataServer::with_host(&host)
+    }
+
+    /// Talks to the metadata server at `host`, given as `host[:port]`
+    pub fn with_host(host: &str) -> MetadataServer {
+        MetadataServer {
+            host: host.to_string(),
+            account: DEFAULT_ACCOUNT.to_string(),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// Fetch tokens for `account`, either an email or `default`
+    pub fn with_account(mut self, account: &str) -> MetadataServer {
+        self.account = account.to_string();
+        self
+    }
+
+    /// Request tokens with `scopes` rather than the scopes the instance was
+    /// created with. Not supported on all platforms, Cloud Run and GKE accept it.
+    pub fn with_scopes(mut self, scopes: &[Scope]) -> MetadataServer {
+        self.scopes = scopes.iter().map(|scope| scope.url()).collect();
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}/computeMetadata/v1/{}", self.host, path)
+    }
+
+    pub(crate) fn token_url(&self) -> String {
+        let url = self.url(&format!("instance/service-accounts/{}/token", self.account));
+        if self.scopes.is_empty() {
+            url
+        } else {
+            format!("{}?scopes={}", url, self.scopes.join(","))
+        }
+    }
+
+    /// Checks whether the metadata server is reachable, this is how we tell
+    /// whether we are running on Google Cloud.
+    pub fn is_available(&self) -> bool {
+        self.is_available_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `is_available`, sending the probe through `client`
+    pub fn is_available_with_http_client(&self, client: &dyn HttpClient) -> bool {
+        let request = HttpRequest::get(&format!("http://{}", self.host))
+            .header(METADATA_FLAVOR, METADATA_FLAVOR_VALUE)
+            .with_timeouts(Timeouts::new().with_total(PROBE_TIMEOUT));
+
+        match client.send(request) {
+            Ok(response) => response.header(METADATA_FLAVOR) == Some(METADATA_FLAVOR_VALUE),
+            Err(_) => false,
+        }
+    }
+
+    /// Fetches an access token for the configured service account
+    pub fn get_token(&self) -> Result<Token> {
+        self.get_token_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `get_token`, sending the request through `client`
+    pub fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        Ok(serde_json::from_str(
+            &self.get_with(client, &self.token_url())?,
+        )?)
+    }
+
+    /// Fetches an ID token for `audience` for the configured service account
+    pub fn get_id_token(&self, audience: &str) -> Result<IdToken> {
+        self.get_id_token_with_http_client(audience, &AttoHttpClient)
+    }
+
+    /// Like `get_id_token`, sending the request through `client`
+    pub fn get_id_token_with_http_client(
+        &self,
+        audience: &str,
+        client: &dyn HttpClient,
+    ) -> Result<IdToken> {
+        let audience =
+            url::form_urlencoded::byte_serialize(audience.as_bytes()).collect::<String>();
+        let url = self.url(&format!(
+            "instance/service-accounts/{}/identity?audience={}&format=full",
+            self.account, audience
+        ));
+        IdToken::from_str(&self.get_with(client, &url)?)
+    }
+
+    /// The email of the configured service account
+    pub fn email(&self) -> Result<String> {
+        self.email_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `email`, sending the request through `client`
+    pub fn email_with_http_client(&self, client: &dyn HttpClient) -> Result<String> {
+        let url = self.url(&format!("instance/service-accounts/{}/email", self.account));
+        self.get_with(client, &url)
+    }
+
+    /// The id of the project the instance belongs to
+    pub fn project_id(&self) -> Result<String> {
+        self.project_id_with_http_client(&AttoHttpClient)
+    }
+
+    /// Like `project_id`, sending the request through `client`
+    pub fn project_id_with_http_client(&self, client: &dyn HttpClient) -> Result<String> {
+        self.get_with(client, &self.url("project/project-id"))
+    }
+
+    fn get_with(&self, client: &dyn HttpClient, url: &str) -> Result<String> {
+        let request = HttpRequest::get(url).header(METADATA_FLAVOR, METADATA_FLAVOR_VALUE);
+        let response = client.send(request)?;
+
+        if response.is_success() {
+            Ok(response.text())
+        } else {
+            let token_err = TokenErr::from_response(response.status(), &response.text());
+            Err(GoErr::endpoint("metadata server", token_err))
+        }
+    }
+}
+
+impl TokenSource for MetadataServer {
+    fn get_token(&self) -> Result<Token> {
+        MetadataServer::get_token(self)
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        MetadataServer::get_token_with_http_client(self, client)
+    }
+}
+
+impl IdTokenSource for MetadataServer {
+    fn get_id_token_with_http_client(
+        &self,
+        audience: &str,
+        client: &dyn HttpClient,
+    ) -> Result<IdToken> {
+        MetadataServer::get_id_token_with_http_client(self, audience, client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::tests::ScriptedClient;
+    use mockito::{self, mock, Matcher};
+
+    const TOKEN: &str = r#"{"access_token":"token","token_type":"Bearer","expires_in":3599}"#;
+
+    fn metadata() -> MetadataServer {
+        MetadataServer::with_host(&mockito::server_address().to_string())
+    }
+
+    #[test]
+    fn default_account_token() {
+        let mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/token",
+        )
+        .match_header("Metadata-Flavor", "Google")
+        .with_status(200)
+        .with_body(TOKEN)
+        .create();
+
+        let token = metadata().get_token().unwrap();
+        assert_eq!(token.access_token(), "token");
+        assert_eq!(token.expires_in(), 3599);
+        mock.assert();
+    }
+
+    #[test]
+    fn account_and_scopes() {
+        let mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/sa@project.iam.gserviceaccount.com/token",
+        )
+        .match_header("Metadata-Flavor", "Google")
+        .match_query(Matcher::UrlEncoded(
+            "scopes".to_string(),
+            Scope::DevStorageReadWrite.url(),
+        ))
+        .with_status(200)
+        .with_body(TOKEN)
+        .create();
+
+        metadata()
+            .with_account("sa@project.iam.gserviceaccount.com")
+            .with_scopes(&[Scope::DevStorageReadWrite])
+            .get_token()
+            .unwrap();
+        mock.assert();
+    }
+
+    #[test]
+    fn error_status() {
+        let _mock = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/missing/token",
+        )
+        .with_status(404)
+        .with_body("not found")
+        .create();
+
+        let err = metadata().with_account("missing").get_token().unwrap_err();
+        assert!(err.to_string().contains("404"));
+    }
+
+    #[test]
+    fn available_when_flavor_header_is_returned() {
+        let _mock = mock("GET", "/")
+            .match_header("Metadata-Flavor", "Google")
+            .with_status(200)
+            .with_header("Metadata-Flavor", "Google")
+            .create();
+
+        assert!(metadata().is_available_with_http_client(&AttoHttpClient));
+        assert!(!MetadataServer::with_host("127.0.0.1:1").is_available());
+    }
+
+    #[test]
+    fn project_id() {
+        let _mock = mock("GET", "/computeMetadata/v1/project/project-id")
+            .match_header("Metadata-Flavor", "Google")
+            .with_status(200)
+            .with_body("my-project")
+            .create();
+
+        assert_eq!(metadata().project_id().unwrap(), "my-project");
+    }
+
+    #[test]
+    fn email_through_http_client() {
+        let client = ScriptedClient::new(&[(200, "sa@project.iam.gserviceaccount.com")]);
+
+        let email = metadata().email_with_http_client(&client).unwrap();
+        assert_eq!(email, "sa@project.iam.gserviceaccount.com");
+        assert_eq!(client.attempts(), 1);
+    }
+}
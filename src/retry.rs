@@ -0,0 +1,261 @@
+//! Retries of token requests that failed for transient reasons.
+//!
+//! A `RetryPolicy` retries network errors and `5xx`/`429` responses with
+//! exponential backoff and jitter, up to a number of attempts and an overall
+//! deadline. Other responses, e.g. a `400` with `invalid_grant`, are returned
+//! right away since retrying them can't help.
+
+use crate::http::{HttpClient, HttpRequest, HttpResponse};
+use crate::Result;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts, backing off from 100ms up to 5s, with no deadline
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// A single attempt, no retries
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::default().with_max_attempts(1)
+    }
+
+    /// Attempts in total, including the first one
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The backoff before the first retry, doubling on each retry up to
+    /// `max`. A random jitter of up to half the backoff is taken off each wait.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// No retry is started once `deadline` has passed since the first attempt,
+    /// or if its backoff would end after it
+    pub fn with_deadline(mut self, deadline: Duration) -> RetryPolicy {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// This policy, giving up on retries by `deadline` at the latest
+    pub(crate) fn until(mut self, deadline: Instant) -> RetryPolicy {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        self.deadline = Some(self.deadline.map_or(remaining, |d| d.min(remaining)));
+        self
+    }
+
+    /// Sends `request` through `client`, retrying transient failures. Returns
+    /// the last response or error once out of attempts or time.
+    pub fn send(&self, client: &dyn HttpClient, request: HttpRequest) -> Result<HttpResponse> {
+        let started = Instant::now();
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+
+        loop {
+            let result = client.send(request.clone());
+            if attempt >= self.max_attempts || !is_transient(&result) {
+                return result;
+            }
+
+            let wait = backoff - jitter(backoff / 2);
+            if let Some(deadline) = self.deadline {
+                if started.elapsed() + wait > deadline {
+                    return result;
+                }
+            }
+            match &result {
+                Ok(response) => log::debug!(
+                    "token request returned {}, retrying in {:?}",
+                    response.status(),
+                    wait
+                ),
+                Err(e) => log::debug!("token request failed, retrying in {:?}: {}", wait, e),
+            }
+
+            thread::sleep(wait);
+            backoff = (backoff * 2).min(self.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+/// Network errors, server errors and rate limiting are worth retrying
+fn is_transient(result: &Result<HttpResponse>) -> bool {
+    match result {
+        Ok(response) => response.status() >= 500 || response.status() == 429,
+        Err(e) => e.is_retryable(),
+    }
+}
+
+/// A random duration between zero and `max`
+pub(crate) fn jitter(max: Duration) -> Duration {
+    max.mul_f64(fastrand::f64())
+}
+
+/// An `HttpClient` that sends requests through another one according to a
+/// `RetryPolicy`, so retries apply wherever a client is accepted
+pub struct RetryingClient {
+    inner: Arc<dyn HttpClient>,
+    policy: RetryPolicy,
+}
+
+impl RetryingClient {
+    pub fn new(inner: Arc<dyn HttpClient>, policy: RetryPolicy) -> RetryingClient {
+        RetryingClient { inner, policy }
+    }
+}
+
+impl HttpClient for RetryingClient {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        self.policy.send(self.inner.as_ref(), request)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::http::TransportErr;
+    use crate::GoErr;
+    use std::sync::Mutex;
+
+    /// Replies with each of `responses` in turn, a status of 0 standing for a
+    /// network error
+    pub(crate) struct ScriptedClient {
+        responses: Mutex<Vec<(u16, String)>>,
+        pub(crate) attempts: Mutex<u32>,
+    }
+
+    impl ScriptedClient {
+        pub(crate) fn new(responses: &[(u16, &str)]) -> ScriptedClient {
+            let mut responses: Vec<_> = responses
+                .iter()
+                .map(|(status, body)| (*status, body.to_string()))
+                .collect();
+            responses.reverse();
+            ScriptedClient {
+                responses: Mutex::new(responses),
+                attempts: Mutex::new(0),
+            }
+        }
+
+        pub(crate) fn attempts(&self) -> u32 {
+            *self.attempts.lock().unwrap()
+        }
+    }
+
+    impl HttpClient for ScriptedClient {
+        fn send(&self, _request: HttpRequest) -> Result<HttpResponse> {
+            *self.attempts.lock().unwrap() += 1;
+            match self.responses.lock().unwrap().pop() {
+                Some((0, _)) => Err(GoErr::from(TransportErr::new("connection reset", true))),
+                Some((status, body)) => Ok(HttpResponse::new(status, body.as_bytes().to_vec())),
+                None => panic!("unexpected request"),
+            }
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new().with_backoff(Duration::from_millis(1), Duration::from_millis(4))
+    }
+
+    fn request() -> HttpRequest {
+        HttpRequest::post_form("https://example.com/token", &[])
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let client = ScriptedClient::new(&[(503, ""), (0, ""), (200, "ok")]);
+        let response = policy().send(&client, request()).unwrap();
+
+        assert_eq!(response.text(), "ok");
+        assert_eq!(client.attempts(), 3);
+    }
+
+    #[test]
+    fn does_not_retry_request_errors() {
+        struct InvalidRequest(Mutex<u32>);
+
+        impl HttpClient for InvalidRequest {
+            fn send(&self, _request: HttpRequest) -> Result<HttpResponse> {
+                *self.0.lock().unwrap() += 1;
+                Err(GoErr::from("invalid header name"))
+            }
+        }
+
+        let client = InvalidRequest(Mutex::new(0));
+        assert!(policy().send(&client, request()).is_err());
+        assert_eq!(*client.0.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn does_not_retry_invalid_grant() {
+        let client = ScriptedClient::new(&[(400, r#"{"error":"invalid_grant"}"#)]);
+        let response = policy().send(&client, request()).unwrap();
+
+        assert_eq!(response.status(), 400);
+        assert_eq!(client.attempts(), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let client = ScriptedClient::new(&[(429, ""), (429, "")]);
+        let response = policy()
+            .with_max_attempts(2)
+            .send(&client, request())
+            .unwrap();
+
+        assert_eq!(response.status(), 429);
+        assert_eq!(client.attempts(), 2);
+    }
+
+    #[test]
+    fn gives_up_at_deadline() {
+        let client = ScriptedClient::new(&[(500, ""), (500, "")]);
+        let response = RetryPolicy::new()
+            .with_backoff(Duration::from_millis(50), Duration::from_millis(50))
+            .with_deadline(Duration::from_millis(10))
+            .send(&client, request())
+            .unwrap();
+
+        assert_eq!(response.status(), 500);
+        assert_eq!(client.attempts(), 1);
+    }
+}
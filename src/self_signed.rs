@@ -0,0 +1,172 @@
+//! Self-signed JWTs, sent to Google APIs directly as bearer tokens.
+//!
+//! A service account can sign a JWT whose `aud` is the API it calls, e.g.
+//! `https://pubsub.googleapis.com/`, or which carries `scope`s, and use it in
+//! place of an access token. This skips the round trip to the token endpoint
+//! altogether.
+
+use crate::auth::{JwtClaims, Token};
+use crate::credentials::Credentials;
+use crate::http::HttpClient;
+use crate::scopes::Scope;
+use crate::signer;
+use crate::source::TokenSource;
+use crate::{GoErr, Result};
+
+use time::{Duration, OffsetDateTime};
+
+/// The longest lifetime Google accepts for a self-signed JWT
+const MAX_LIFETIME_SECONDS: i64 = 3600;
+
+/// Signs JWTs that are valid for a single API audience or a set of scopes.
+/// Wrap it in `TokenFetcher::with_self_signed_jwt` to cache the signed token.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::credentials::Credentials;
+/// use goauth::self_signed::SelfSignedJwt;
+///
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let jwt = SelfSignedJwt::with_audience(credentials, "https://pubsub.googleapis.com/");
+/// let token = jwt.get_token().unwrap();
+/// println!("Authorization: Bearer {}", token.access_token());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SelfSignedJwt {
+    credentials: Credentials,
+    claims: JwtClaims,
+}
+
+impl SelfSignedJwt {
+    /// JWTs valid for the API at `audience`, e.g. `https://pubsub.googleapis.com/`
+    pub fn with_audience(credentials: Credentials, audience: &str) -> SelfSignedJwt {
+        let claims = JwtClaims::self_signed(
+            credentials.iss(),
+            &[],
+            Some(audience.to_string()),
+            MAX_LIFETIME_SECONDS,
+        );
+        SelfSignedJwt {
+            credentials,
+            claims,
+        }
+    }
+
+    /// JWTs valid for every API covered by `scopes`
+    pub fn with_scopes(credentials: Credentials, scopes: &[Scope]) -> SelfSignedJwt {
+        let claims = JwtClaims::self_signed(credentials.iss(), scopes, None, MAX_LIFETIME_SECONDS);
+        SelfSignedJwt {
+            credentials,
+            claims,
+        }
+    }
+
+    /// How long each signed JWT is valid for, at most an hour
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Result<SelfSignedJwt> {
+        let seconds = lifetime.whole_seconds();
+        if seconds <= 0 || seconds > MAX_LIFETIME_SECONDS {
+            return Err(GoErr::from(
+                format!(
+                    "self-signed JWT lifetime must be between 1 and {} seconds, got {}",
+                    MAX_LIFETIME_SECONDS, seconds
+                )
+                .as_str(),
+            ));
+        }
+        self.claims.update(None, Some(seconds));
+        Ok(self)
+    }
+
+    pub fn claims(&self) -> &JwtClaims {
+        &self.claims
+    }
+
+    /// Signs a JWT valid from now, with unpadded base64url segments. The
+    /// `Token` carries the JWT as its `access_token` and its lifetime as
+    /// `expires_in`.
+    pub fn get_token(&self) -> Result<Token> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let claims = self.claims.refresh(Some(now), None);
+        let expires_in = claims.lifetime() as u32;
+
+        Ok(Token::new(
+            signer::encode_unpadded(&claims, &self.credentials)?,
+            "Bearer".to_string(),
+            expires_in,
+        ))
+    }
+}
+
+impl TokenSource for SelfSignedJwt {
+    fn get_token(&self) -> Result<Token> {
+        SelfSignedJwt::get_token(self)
+    }
+
+    /// Signing is local, there are no requests to send through `client`
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        let _ = client;
+        SelfSignedJwt::get_token(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    fn credentials() -> Credentials {
+        Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap()
+    }
+
+    fn payload(token: &Token) -> serde_json::Value {
+        let payload = token.access_token().split('.').nth(1).unwrap();
+        let payload = URL_SAFE_NO_PAD.decode(payload).unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn audience_claims() {
+        let credentials = credentials();
+        let iss = credentials.iss();
+        let token = SelfSignedJwt::with_audience(credentials, "https://pubsub.googleapis.com/")
+            .get_token()
+            .unwrap();
+
+        let claims = payload(&token);
+        assert_eq!(claims["iss"], iss.as_str());
+        assert_eq!(claims["sub"], iss.as_str());
+        assert_eq!(claims["aud"], "https://pubsub.googleapis.com/");
+        assert!(claims.get("scope").is_none());
+        assert_eq!(token.token_type(), "Bearer");
+        assert_eq!(token.expires_in(), 3600);
+    }
+
+    #[test]
+    fn scope_claims() {
+        let token = SelfSignedJwt::with_scopes(credentials(), &[Scope::CloudPlatform])
+            .with_lifetime(Duration::new(600, 0))
+            .unwrap()
+            .get_token()
+            .unwrap();
+
+        let claims = payload(&token);
+        assert_eq!(claims["scope"], Scope::CloudPlatform.url().as_str());
+        assert!(claims.get("aud").is_none());
+        assert_eq!(
+            claims["exp"].as_i64().unwrap() - claims["iat"].as_i64().unwrap(),
+            600
+        );
+        assert_eq!(token.expires_in(), 600);
+    }
+
+    #[test]
+    fn lifetime_is_capped() {
+        assert!(
+            SelfSignedJwt::with_scopes(credentials(), &[Scope::CloudPlatform])
+                .with_lifetime(Duration::new(7200, 0))
+                .is_err()
+        );
+    }
+}
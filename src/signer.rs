@@ -0,0 +1,613 @@
+//! Signing of the JWTs that are exchanged for access tokens, or used as bearer
+//! tokens themselves.
+//!
+//! A `Signer` turns the encoded header and claims into a signature, the crate
+//! does the JWT encoding around it. `RsaSigner` signs with a local private
+//! key, and `Credentials` sign with theirs. `IamSigner` signs through the IAM
+//! Credentials API with keys that never leave Google. Keys in Cloud KMS or an
+//! HSM only need an implementation of `sign`.
+
+use crate::auth::TokenErr;
+use crate::http::{default_client, HttpClient, HttpRequest};
+use crate::impersonate::IAM_CREDENTIALS_URL;
+use crate::source::TokenSource;
+use crate::{GoErr, Result};
+
+use base64::engine::general_purpose::{GeneralPurpose, STANDARD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// The only algorithm Google's token endpoint accepts
+pub const RS256: &str = "RS256";
+
+/// Signs JWTs.
+///
+/// The JWT header is built by `header` from `algorithm` and `key_id`, so a
+/// custom signer must report the algorithm its signatures are made with, and
+/// the id of the key that makes them if it is known up front. Google checks an `RS256`
+/// signature against the public key of the service account named by `kid`,
+/// or against each of its keys when there is no `kid`.
+///
+/// ### Example
+///
+/// ```rust
+/// use goauth::signer::{Signer, RS256};
+/// use goauth::Result;
+///
+/// struct HsmSigner;
+///
+/// impl Signer for HsmSigner {
+///     fn algorithm(&self) -> &str {
+///         RS256
+///     }
+///
+///     fn key_id(&self) -> Option<&str> {
+///         Some("0123456789abcdef")
+///     }
+///
+///     fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+///         // An RSASSA-PKCS1-v1_5 SHA-256 signature of `message`, made by the HSM
+///         # let _ = message;
+///         # Ok(Vec::new())
+///     }
+/// }
+/// ```
+pub trait Signer: Send + Sync {
+    /// The JWS `alg` of the signatures, e.g. `RS256`
+    fn algorithm(&self) -> &str;
+
+    /// The `kid` of the JWT header, the id of the signing key
+    fn key_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// Signs `message`, the encoded header and claims of a JWT joined by a
+    /// `.`, returning the raw signature
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+
+    /// The JWT header, `alg` and `kid` from `algorithm` and `key_id`. Add
+    /// fields to it and sign with `encode_with_header` for custom headers.
+    fn header(&self) -> JwtHeader {
+        let header = JwtHeader::new(self.algorithm());
+        match self.key_id() {
+            Some(key_id) => header.with_key_id(key_id),
+            None => header,
+        }
+    }
+
+    /// Encodes `claims`, a JSON object, into a signed JWT. Signers that build
+    /// the whole JWT themselves, like `IamSigner`, override this.
+    fn sign_jwt(&self, claims: &str) -> Result<String> {
+        sign_with_header(&self.header(), claims, self, &URL_SAFE)
+    }
+}
+
+impl<T: Signer + ?Sized> Signer for Arc<T> {
+    fn algorithm(&self) -> &str {
+        (**self).algorithm()
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        (**self).key_id()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        (**self).sign(message)
+    }
+
+    fn header(&self) -> JwtHeader {
+        (**self).header()
+    }
+
+    fn sign_jwt(&self, claims: &str) -> Result<String> {
+        (**self).sign_jwt(claims)
+    }
+}
+
+/// The header of a JWT, serialized as `alg`, `typ`, `kid` and then any
+/// custom fields
+///
+/// ### Example
+///
+/// ```rust
+/// use goauth::signer::{JwtHeader, RS256};
+///
+/// let header = JwtHeader::new(RS256)
+///     .with_key_id("0123456789abcdef")
+///     .with_field("x5u", "https://example.com/certs");
+/// assert_eq!(
+///     serde_json::to_string(&header).unwrap(),
+///     r#"{"alg":"RS256","typ":"JWT","kid":"0123456789abcdef","x5u":"https://example.com/certs"}"#
+/// );
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JwtHeader {
+    alg: String,
+    typ: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+    #[serde(flatten)]
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl JwtHeader {
+    /// A header of type `JWT` signed with `alg`
+    pub fn new(alg: &str) -> JwtHeader {
+        JwtHeader {
+            alg: alg.to_string(),
+            typ: "JWT".to_string(),
+            kid: None,
+            fields: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_key_id(mut self, kid: &str) -> JwtHeader {
+        self.kid = Some(kid.to_string());
+        self
+    }
+
+    /// Sets a custom field. `alg`, `typ` and `kid` can't be set this way and
+    /// are ignored.
+    pub fn with_field<V: Into<serde_json::Value>>(mut self, name: &str, value: V) -> JwtHeader {
+        match name {
+            "alg" | "typ" | "kid" => log::warn!("ignoring JWT header field `{}`", name),
+            _ => {
+                self.fields.insert(name.to_string(), value.into());
+            }
+        }
+        self
+    }
+
+    pub fn algorithm(&self) -> &str {
+        &self.alg
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.kid.as_deref()
+    }
+
+    pub fn field(&self, name: &str) -> Option<&serde_json::Value> {
+        self.fields.get(name)
+    }
+
+    /// The first segment of a JWT made by `encode`, the padded base64url
+    /// encoded header
+    pub fn encode(&self) -> Result<String> {
+        Ok(URL_SAFE.encode(serde_json::to_string(self)?))
+    }
+}
+
+/// Encodes `claims` into a JWT signed by `signer`
+pub fn encode<T: Serialize + ?Sized>(claims: &T, signer: &dyn Signer) -> Result<String> {
+    signer.sign_jwt(&serde_json::to_string(claims)?)
+}
+
+/// Encodes `claims` into a JWT with the given header, signed by the `sign` of
+/// `signer`. The header is usually `signer.header()` with fields added, its
+/// `alg` must be the algorithm of the signer.
+///
+/// ### Example
+///
+/// ```rust
+/// use goauth::auth::JwtClaims;
+/// use goauth::credentials::Credentials;
+/// use goauth::scopes::Scope;
+/// use goauth::signer::{encode_with_header, Signer};
+///
+/// let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+/// let claims = JwtClaims::new(credentials.iss(),
+///                             &[Scope::DevStorageReadWrite],
+///                             credentials.token_uri(),
+///                             None, None);
+/// let header = credentials.header().with_field("jku", "https://example.com/jwks");
+/// let jwt = encode_with_header(&header, &claims, &credentials).unwrap();
+/// ```
+pub fn encode_with_header<T: Serialize + ?Sized>(
+    header: &JwtHeader,
+    claims: &T,
+    signer: &dyn Signer,
+) -> Result<String> {
+    sign_with_header(header, &serde_json::to_string(claims)?, signer, &URL_SAFE)
+}
+
+/// Like `encode`, with the unpadded base64url segments of RFC 7515. JWTs sent
+/// to Google APIs as bearer tokens, like those of `SelfSignedJwt`, are encoded
+/// this way, the token endpoint also accepts the padded segments of `encode`.
+/// Always signs through `Signer::sign`, even for signers that override
+/// `sign_jwt`.
+pub fn encode_unpadded<T: Serialize + ?Sized>(claims: &T, signer: &dyn Signer) -> Result<String> {
+    sign_with_header(
+        &signer.header(),
+        &serde_json::to_string(claims)?,
+        signer,
+        &URL_SAFE_NO_PAD,
+    )
+}
+
+fn sign_with_header<S: Signer + ?Sized>(
+    header: &JwtHeader,
+    claims: &str,
+    signer: &S,
+    engine: &GeneralPurpose,
+) -> Result<String> {
+    let header = engine.encode(serde_json::to_string(header)?);
+    let input = format!("{}.{}", header, engine.encode(claims));
+    let signature = signer.sign(input.as_bytes())?;
+
+    Ok(format!("{}.{}", input, engine.encode(signature)))
+}
+
+#[cfg(feature = "rustcrypto")]
+type RsaPrivateKey = crate::jwt::RsaKey;
+#[cfg(all(feature = "openssl", not(feature = "rustcrypto")))]
+type RsaPrivateKey = openssl::pkey::PKey<openssl::pkey::Private>;
+
+/// Signs with a local RSA private key, using OpenSSL with the default
+/// `openssl` feature, or pure Rust with the `rustcrypto` feature
+pub struct RsaSigner {
+    key: RsaPrivateKey,
+    key_id: Option<String>,
+}
+
+impl RsaSigner {
+    /// Reads a PKCS#8 (`BEGIN PRIVATE KEY`) or PKCS#1 (`BEGIN RSA PRIVATE KEY`) PEM
+    pub fn from_pem(pem: &str) -> Result<RsaSigner> {
+        #[cfg(feature = "rustcrypto")]
+        let key = crate::jwt::RsaKey::from_pem(pem)?;
+        #[cfg(all(feature = "openssl", not(feature = "rustcrypto")))]
+        let key = openssl::pkey::PKey::private_key_from_pem(pem.as_bytes())
+            .map_err(|e| GoErr::from(format!("invalid RSA private key: {}", e).as_str()))?;
+
+        Ok(RsaSigner { key, key_id: None })
+    }
+
+    /// The `kid` to put in the headers of the JWTs
+    pub fn with_key_id(mut self, key_id: &str) -> RsaSigner {
+        self.key_id = Some(key_id.to_string());
+        self
+    }
+}
+
+impl Signer for RsaSigner {
+    fn algorithm(&self) -> &str {
+        RS256
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    #[cfg(feature = "rustcrypto")]
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.key.sign(message)
+    }
+
+    #[cfg(all(feature = "openssl", not(feature = "rustcrypto")))]
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let failed = |e: openssl::error::ErrorStack| {
+            GoErr::from(format!("RS256 signing failed: {}", e).as_str())
+        };
+        let mut signer =
+            openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &self.key)
+                .map_err(failed)?;
+        signer.update(message).map_err(failed)?;
+        signer.sign_to_vec().map_err(failed)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignRequest<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    delegates: &'a [String],
+    payload: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignJwtResponse {
+    signed_jwt: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignBlobResponse {
+    signed_blob: String,
+}
+
+/// Signs with the Google-managed keys of a service account through the IAM
+/// Credentials `signJwt` and `signBlob` methods, so there is no key to export.
+/// The identity of `source` needs the `roles/iam.serviceAccountTokenCreator`
+/// role on the service account.
+///
+/// `signJwt` picks the key and sets the `kid` itself, so `key_id` is `None`.
+///
+/// The signing requests go through `attohttpc` by default, pass a client
+/// wrapped in a `TimeoutClient` or `RetryingClient` to `with_http_client` to
+/// bound or retry them.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::auth::JwtClaims;
+/// use goauth::fetcher::TokenFetcher;
+/// use goauth::metadata::MetadataServer;
+/// use goauth::scopes::Scope;
+/// use goauth::signer::IamSigner;
+/// use std::sync::Arc;
+/// use time::Duration;
+///
+/// let email = "signer@my-project.iam.gserviceaccount.com";
+/// let source = TokenFetcher::with_metadata_server(MetadataServer::new(), Duration::new(60, 0));
+/// let signer = IamSigner::new(Arc::new(source), email);
+///
+/// let claims = JwtClaims::new(
+///     email.to_string(),
+///     &[Scope::DevStorageReadWrite],
+///     "https://oauth2.googleapis.com/token".to_string(),
+///     None,
+///     None,
+/// );
+/// let fetcher = TokenFetcher::with_signer(claims, Arc::new(signer), Duration::new(60, 0));
+/// let token = fetcher.fetch_token().unwrap();
+/// ```
+pub struct IamSigner {
+    source: Arc<dyn TokenSource>,
+    // pub(crate) so this can be overriden in tests
+    pub(crate) url: String,
+    delegates: Vec<String>,
+    http_client: Arc<dyn HttpClient>,
+}
+
+impl IamSigner {
+    pub fn new(source: Arc<dyn TokenSource>, service_account: &str) -> IamSigner {
+        IamSigner {
+            source,
+            url: format!("{}/{}", IAM_CREDENTIALS_URL, service_account),
+            delegates: Vec::new(),
+            http_client: default_client(),
+        }
+    }
+
+    /// Service accounts in the delegation chain, each needs the token creator
+    /// role on the next, the last one on the signing service account
+    pub fn with_delegates(mut self, delegates: &[&str]) -> IamSigner {
+        self.delegates = delegates
+            .iter()
+            .map(|delegate| format!("projects/-/serviceAccounts/{}", delegate))
+            .collect();
+        self
+    }
+
+    /// Sends the signing requests through `client` instead of the default
+    /// `attohttpc` transport
+    pub fn with_http_client(mut self, client: Arc<dyn HttpClient>) -> IamSigner {
+        self.http_client = client;
+        self
+    }
+
+    fn call<R: DeserializeOwned>(&self, method: &str, payload: &str) -> Result<R> {
+        let token = self.source.get_token()?;
+        let request = SignRequest {
+            delegates: &self.delegates,
+            payload,
+        };
+
+        let request = HttpRequest::post_json(&format!("{}:{}", self.url, method), &request)?
+            .bearer_auth(token.access_token());
+        let response = self.http_client.send(request)?;
+
+        if !response.is_success() {
+            let token_err = TokenErr::from_response(response.status(), &response.text());
+            return Err(GoErr::endpoint(method, token_err));
+        }
+        response.json::<R>()
+    }
+}
+
+impl Signer for IamSigner {
+    fn algorithm(&self) -> &str {
+        RS256
+    }
+
+    /// Signs through `signBlob`
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let response: SignBlobResponse = self.call("signBlob", &STANDARD.encode(message))?;
+        STANDARD
+            .decode(&response.signed_blob)
+            .map_err(|e| GoErr::from(format!("invalid signedBlob: {}", e).as_str()))
+    }
+
+    /// Signs through `signJwt`, which also builds the header
+    fn sign_jwt(&self, claims: &str) -> Result<String> {
+        let response: SignJwtResponse = self.call("signJwt", claims)?;
+        Ok(response.signed_jwt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{JwtClaims, Token};
+    use crate::credentials::Credentials;
+    use crate::fetcher::TokenFetcher;
+    use crate::scopes::Scope;
+    use mockito::{self, mock, Matcher};
+    use time::Duration;
+
+    struct StaticSource;
+
+    impl TokenSource for StaticSource {
+        fn get_token(&self) -> Result<Token> {
+            Ok(Token::new(
+                "base_token".to_string(),
+                "Bearer".to_string(),
+                3600,
+            ))
+        }
+    }
+
+    fn iam_signer(path: &str) -> IamSigner {
+        let mut signer = IamSigner::new(
+            Arc::new(StaticSource),
+            "signer@project.iam.gserviceaccount.com",
+        );
+        signer.url = format!("{}/{}", mockito::server_url(), path);
+        signer
+    }
+
+    fn claims(aud: &str) -> JwtClaims {
+        JwtClaims::new(
+            "some_iss".to_string(),
+            &[Scope::DevStorageReadWrite],
+            aud.to_string(),
+            Some(1482317385),
+            Some(3600),
+        )
+    }
+
+    #[test]
+    fn rsa_signer_matches_smpl_jwt() {
+        let pem = std::fs::read_to_string("random_rsa_for_testing").unwrap();
+        let signer = RsaSigner::from_pem(&pem).unwrap();
+
+        assert_eq!(encode(&claims("https://www.googleapis.com/oauth2/v4/token"), &signer).unwrap(), "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJpc3MiOiJzb21lX2lzcyIsInNjb3BlIjoiaHR0cHM6Ly93d3cuZ29vZ2xlYXBpcy5jb20vYXV0aC9kZXZzdG9yYWdlLnJlYWRfd3JpdGUiLCJhdWQiOiJodHRwczovL3d3dy5nb29nbGVhcGlzLmNvbS9vYXV0aDIvdjQvdG9rZW4iLCJleHAiOjE0ODIzMjA5ODUsImlhdCI6MTQ4MjMxNzM4NX0=.BldQozpzNYnLnYWBbqwAWY1j2hPDD3oVY9EOG0eRJN77sC4ZInEyGJT5eXLD39C726TdrEVCHmvhKBJFmaFL2BXNto69_v8lz-3oGnFL5FkUr4RRpukd_6tj7-RZzx15LIzdTqzKfAUlqWoZUdze8Fcd1NJ6w1g49CCghvN_eryvecALpjnHoBkKlIXnSm_udiSf26cYWvCikmW5g8nUqAduFsIYfR-4LMwyUfYH1hNC64SRsfLH9bL4-tyeaoUCv5MXTIhxrJbrhQy3TEOSc5didDrMoYNUu_qjJvxBQbq1Um1W1SpyvSd4eVJn18xZcOmCnoE73RDZcxT5hDpaRQ==");
+    }
+
+    #[test]
+    fn unpadded_segments() {
+        let pem = std::fs::read_to_string("random_rsa_for_testing").unwrap();
+        let signer = RsaSigner::from_pem(&pem).unwrap();
+        let claims = claims("https://www.googleapis.com/oauth2/v4/token");
+
+        let padded = encode(&claims, &signer).unwrap();
+        let unpadded = encode_unpadded(&claims, &signer).unwrap();
+        assert!(!unpadded.contains('='));
+        // The signatures differ, as they are over the differently encoded segments
+        for (padded, unpadded) in padded.split('.').zip(unpadded.split('.')).take(2) {
+            assert_eq!(
+                URL_SAFE.decode(padded).unwrap(),
+                URL_SAFE_NO_PAD.decode(unpadded).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn key_id_in_header() {
+        let pem = std::fs::read_to_string("random_rsa_for_testing").unwrap();
+        let signer = RsaSigner::from_pem(&pem).unwrap().with_key_id("abc123");
+
+        let jwt = encode(&claims("aud"), &signer).unwrap();
+        let header = jwt.split('.').next().unwrap();
+        assert_eq!(
+            URL_SAFE.decode(header).unwrap(),
+            br#"{"alg":"RS256","typ":"JWT","kid":"abc123"}"#
+        );
+    }
+
+    #[test]
+    fn credentials_key_id_and_custom_fields() {
+        let credentials = Credentials::from_file("dummy_credentials_file_for_tests.json").unwrap();
+        let header = credentials
+            .header()
+            .with_field("jku", "https://example.com/jwks");
+
+        let jwt = encode_with_header(&header, &claims("aud"), &credentials).unwrap();
+        let encoded_header = jwt.split('.').next().unwrap();
+        assert_eq!(
+            URL_SAFE.decode(encoded_header).unwrap(),
+            br#"{"alg":"RS256","typ":"JWT","kid":"dummy","jku":"https://example.com/jwks"}"#
+        );
+
+        let jwt = credentials.sign_jwt(&claims("aud")).unwrap();
+        let encoded_header = jwt.split('.').next().unwrap();
+        let decoded: JwtHeader =
+            serde_json::from_slice(&URL_SAFE.decode(encoded_header).unwrap()).unwrap();
+        assert_eq!(decoded, JwtHeader::new(RS256).with_key_id("dummy"));
+    }
+
+    #[test]
+    fn iam_sign_blob() {
+        let _mock = mock("POST", "/sign_blob:signBlob")
+            .match_header("authorization", "Bearer base_token")
+            .match_body(Matcher::Json(serde_json::json!({ "payload": "aGVsbG8=" })))
+            .with_status(200)
+            .with_body(r#"{"keyId": "key", "signedBlob": "c2lnbmF0dXJl"}"#)
+            .create();
+
+        assert_eq!(
+            iam_signer("sign_blob").sign(b"hello").unwrap(),
+            b"signature"
+        );
+    }
+
+    #[test]
+    fn iam_sign_jwt_error() {
+        let _mock = mock("POST", "/sign_denied:signJwt")
+            .with_status(403)
+            .with_body(r#"{"error": {"code": 403, "message": "Permission 'iam.serviceAccounts.signJwt' denied", "status": "PERMISSION_DENIED"}}"#)
+            .create();
+
+        let err = encode(&claims("aud"), &iam_signer("sign_denied")).unwrap_err();
+        assert_eq!(err.status(), Some(403));
+        assert!(err.is_auth_misconfiguration());
+    }
+
+    #[test]
+    fn iam_signer_retries_through_its_http_client() {
+        use crate::retry::tests::ScriptedClient;
+        use crate::retry::{RetryPolicy, RetryingClient};
+
+        let client = Arc::new(ScriptedClient::new(&[
+            (503, ""),
+            (
+                200,
+                r#"{"keyId": "key", "signedJwt": "header.claims.signature"}"#,
+            ),
+        ]));
+        let policy = RetryPolicy::new().with_backoff(
+            std::time::Duration::from_millis(1),
+            std::time::Duration::from_millis(4),
+        );
+        let signer = iam_signer("unused")
+            .with_http_client(Arc::new(RetryingClient::new(client.clone(), policy)));
+
+        assert_eq!(
+            encode(&claims("aud"), &signer).unwrap(),
+            "header.claims.signature"
+        );
+        assert_eq!(client.attempts(), 2);
+    }
+
+    #[test]
+    fn fetcher_with_iam_signer() {
+        let token_url = format!("{}/iam_signed_token", mockito::server_url());
+        let _sign = mock("POST", "/iam_signed:signJwt")
+            .match_header("authorization", "Bearer base_token")
+            .match_body(Matcher::Regex(
+                r#""payload":"\{\\"iss\\":\\"some_iss\\""#.to_string(),
+            ))
+            .with_status(200)
+            .with_body(r#"{"keyId": "key", "signedJwt": "header.claims.signature"}"#)
+            .create();
+        let _token = mock("POST", "/iam_signed_token")
+            .match_body(Matcher::UrlEncoded(
+                "assertion".to_string(),
+                "header.claims.signature".to_string(),
+            ))
+            .with_status(200)
+            .with_body(
+                r#"{"access_token": "iam_signed", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .create();
+
+        let fetcher = TokenFetcher::with_signer(
+            claims(&token_url),
+            Arc::new(iam_signer("iam_signed")),
+            Duration::new(60, 0),
+        );
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "iam_signed");
+    }
+}
@@ -0,0 +1,197 @@
+//! A common interface over everything that produces access tokens.
+//!
+//! Service account keys, the metadata server, user and external account
+//! credentials, impersonation and self-signed JWTs all implement
+//! `TokenSource`, as does `TokenFetcher`, which caches the tokens of any
+//! other source. Accept an `Arc<dyn TokenSource>` to work with all of them,
+//! and with fakes in tests.
+
+use crate::auth::Token;
+use crate::http::HttpClient;
+use crate::Result;
+
+use std::sync::Arc;
+
+/// Produces access tokens.
+///
+/// ### Example
+///
+/// ```rust no_run
+/// use goauth::fetcher::TokenFetcher;
+/// use goauth::metadata::MetadataServer;
+/// use goauth::source::TokenSource;
+/// use std::sync::Arc;
+/// use time::Duration;
+///
+/// fn list_buckets(auth: Arc<dyn TokenSource>) {
+///     let header = auth.authorization_header().unwrap();
+///     // ... send a request with `Authorization: {header}`
+/// }
+///
+/// let fetcher = TokenFetcher::with_metadata_server(MetadataServer::new(), Duration::new(60, 0));
+/// list_buckets(Arc::new(fetcher));
+/// ```
+pub trait TokenSource: Send + Sync {
+    /// Fetches a token, which for caching sources may be a stored one
+    fn get_token(&self) -> Result<Token>;
+
+    /// Like `get_token`, sending any requests through `client`. Sources that
+    /// make no requests ignore it, those that bring their own transport like
+    /// `TokenFetcher` only keep to its deadline.
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        let _ = client;
+        self.get_token()
+    }
+
+    /// The value of an `Authorization` header carrying a token, e.g.
+    /// `Bearer ya29.abc`
+    fn authorization_header(&self) -> Result<String> {
+        let token = self.get_token()?;
+        Ok(format!("{} {}", token.token_type(), token.access_token()))
+    }
+}
+
+impl<T: TokenSource + ?Sized> TokenSource for Arc<T> {
+    fn get_token(&self) -> Result<Token> {
+        (**self).get_token()
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        (**self).get_token_with_http_client(client)
+    }
+
+    fn authorization_header(&self) -> Result<String> {
+        (**self).authorization_header()
+    }
+}
+
+impl<T: TokenSource + ?Sized> TokenSource for Box<T> {
+    fn get_token(&self) -> Result<Token> {
+        (**self).get_token()
+    }
+
+    fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+        (**self).get_token_with_http_client(client)
+    }
+
+    fn authorization_header(&self) -> Result<String> {
+        (**self).authorization_header()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::fetcher::TokenFetcher;
+    use crate::http::{HttpRequest, HttpResponse};
+    use crate::retry::RetryPolicy;
+    use crate::GoErr;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration as StdDuration;
+    use time::Duration;
+
+    /// Hands out numbered tokens, after a request to the client it is given.
+    /// Also an `AsyncTokenSource`, for the async fetcher's tests.
+    pub(crate) struct FakeSource {
+        calls: AtomicUsize,
+    }
+
+    impl FakeSource {
+        pub(crate) fn new() -> FakeSource {
+            FakeSource {
+                calls: AtomicUsize::new(0),
+            }
+        }
+
+        pub(crate) fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn next_token(&self) -> Token {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Token::new(format!("fake_{}", call), "Bearer".to_string(), 3600)
+        }
+    }
+
+    impl TokenSource for FakeSource {
+        fn get_token(&self) -> Result<Token> {
+            Ok(self.next_token())
+        }
+
+        fn get_token_with_http_client(&self, client: &dyn HttpClient) -> Result<Token> {
+            let response = client.send(HttpRequest::get("http://fake.invalid/token"))?;
+            if !response.is_success() {
+                return Err(GoErr::from("fake token endpoint failed"));
+            }
+            self.get_token()
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl crate::asynchronous::AsyncTokenSource for FakeSource {
+        fn get_token(&self) -> crate::asynchronous::BoxFuture<'_, Result<Token>> {
+            let token = self.next_token();
+            Box::pin(async move { Ok(token) })
+        }
+    }
+
+    /// Fails the first `failures` requests with a `503`
+    struct FakeClient {
+        failures: usize,
+        requests: AtomicUsize,
+    }
+
+    impl FakeClient {
+        fn new(failures: usize) -> FakeClient {
+            FakeClient {
+                failures,
+                requests: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl HttpClient for FakeClient {
+        fn send(&self, _request: HttpRequest) -> Result<HttpResponse> {
+            let status = if self.requests.fetch_add(1, Ordering::SeqCst) < self.failures {
+                503
+            } else {
+                200
+            };
+            Ok(HttpResponse::new(status, Vec::new()))
+        }
+    }
+
+    #[test]
+    fn fetcher_caches_any_source() {
+        let source = Arc::new(FakeSource::new());
+        let fetcher = TokenFetcher::with_source(source.clone(), Duration::new(60, 0))
+            .with_http_client(Arc::new(FakeClient::new(0)));
+
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "fake_0");
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "fake_0");
+        assert_eq!(source.calls(), 1);
+    }
+
+    #[test]
+    fn fetcher_retries_any_source() {
+        let source = Arc::new(FakeSource::new());
+        let client = Arc::new(FakeClient::new(1));
+        let fetcher = TokenFetcher::with_source(source, Duration::new(60, 0))
+            .with_http_client(client.clone())
+            .with_retry_policy(
+                RetryPolicy::new()
+                    .with_backoff(StdDuration::from_millis(1), StdDuration::from_millis(1)),
+            );
+
+        assert_eq!(fetcher.fetch_token().unwrap().access_token(), "fake_0");
+        assert_eq!(client.requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn authorization_header() {
+        let source: Arc<dyn TokenSource> = Arc::new(FakeSource::new());
+
+        assert_eq!(source.authorization_header().unwrap(), "Bearer fake_0");
+    }
+}